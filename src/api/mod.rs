@@ -1,9 +1,11 @@
 use serde::Serialize;
 use crate::blockchain::Blockchain;
-use crate::miner::Handle as MinerHandle;
+use crate::blockchain::difficulty::block_work;
+use crate::blockchain::orphan::OrphanPool;
+use crate::miner::{self, Handle as MinerHandle};
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
-use crate::tx_gen::Handle as TxHandle;
+use crate::tx_gen::{self, Handle as TxHandle};
 use crate::types::mempool::Mempool;
 use crate::types::hash::Hashable;
 use log::info;
@@ -21,6 +23,7 @@ pub struct Server {
     miner: MinerHandle,
     network: NetworkServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
+    orphan_pool: Arc<Mutex<OrphanPool>>,
     tx_handle: TxHandle,
     mempool: Arc<Mutex<Mempool>>,
     state_per_block: Arc<Mutex<StatePerBlock>>,
@@ -32,6 +35,47 @@ struct ApiResponse {
     message: String,
 }
 
+#[derive(Serialize)]
+struct MinerStatus {
+    state: String,
+    lambda: Option<u64>,
+    threads: usize,
+    hashrate: f64,
+    blocks_mined: u64,
+    stale_blocks: u64,
+    last_block: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TxGeneratorStatus {
+    state: String,
+    lambda: Option<u64>,
+    transactions_generated: u64,
+    last_transaction: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PeerStatus {
+    addr: String,
+    outbound: bool,
+    /// Whether the version handshake is complete, the fields below are only known after it
+    handshake_complete: bool,
+    listen_addr: Option<String>,
+    version: Option<u32>,
+    height: Option<u64>,
+    /// Identity key the peer proved, only on encrypted connections
+    identity: Option<String>,
+    score: u32,
+}
+
+#[derive(Serialize)]
+struct BlockWork {
+    hash: String,
+    height: u64,
+    work: String,
+    cumulative_work: String,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -54,11 +98,13 @@ macro_rules! respond_json {
 }
 
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         network: &NetworkServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
+        orphan_pool: &Arc<Mutex<OrphanPool>>,
         tx_handle: &TxHandle,
         mempool: &Arc<Mutex<Mempool>>,
         state_per_block: &Arc<Mutex<StatePerBlock>>
@@ -69,6 +115,7 @@ impl Server {
             miner: miner.clone(),
             network: network.clone(),
             blockchain: Arc::clone(blockchain),
+            orphan_pool: Arc::clone(orphan_pool),
             tx_handle: tx_handle.clone(),
             mempool: Arc::clone(mempool),
             state_per_block: Arc::clone(state_per_block),
@@ -78,6 +125,7 @@ impl Server {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
                 let blockchain = Arc::clone(&server.blockchain);
+                let orphan_pool = Arc::clone(&server.orphan_pool);
                 let state_per_block = Arc::clone(&server.state_per_block);
                 let tx_handle = server.tx_handle.clone();
                 thread::spawn(move || {
@@ -112,8 +160,42 @@ impl Server {
                                     return;
                                 }
                             };
-                            miner.start(lambda);
-                            respond_result!(req, true, "ok");
+                            if miner.start(lambda) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "miner stopped");
+                            }
+                        }
+                        "/miner/pause" => {
+                            if miner.pause() {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "miner stopped");
+                            }
+                        }
+                        "/miner/stop" => {
+                            if miner.exit() {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "miner stopped");
+                            }
+                        }
+                        "/miner/status" => {
+                            let status = miner.status();
+                            let (state, lambda) = match status.state {
+                                miner::OperatingState::Paused => ("paused", None),
+                                miner::OperatingState::Run(i) => ("running", Some(i)),
+                                miner::OperatingState::ShutDown => ("stopped", None),
+                            };
+                            respond_json!(req, MinerStatus {
+                                state: state.to_string(),
+                                lambda,
+                                threads: status.threads,
+                                hashrate: status.hashrate,
+                                blocks_mined: status.blocks_mined,
+                                stale_blocks: status.stale_blocks,
+                                last_block: status.last_block.map(|h| h.to_string()),
+                            });
                         }
                         "/tx-generator/start" => {
                             let params = url.query_pairs();
@@ -136,19 +218,89 @@ impl Server {
                                     return;
                                 }
                             };
-                            tx_handle.start(theta);
-                            respond_result!(req, true, "ok");
+                            if tx_handle.start(theta) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "tx generator stopped");
+                            }
+                        }
+                        "/tx-generator/pause" => {
+                            if tx_handle.pause() {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "tx generator stopped");
+                            }
+                        }
+                        "/tx-generator/stop" => {
+                            if tx_handle.exit() {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "tx generator stopped");
+                            }
+                        }
+                        "/tx-generator/status" => {
+                            let status = tx_handle.status();
+                            let (state, lambda) = match status.state {
+                                tx_gen::OperatingState::Paused => ("paused", None),
+                                tx_gen::OperatingState::Run(i) => ("running", Some(i)),
+                                tx_gen::OperatingState::ShutDown => ("stopped", None),
+                            };
+                            respond_json!(req, TxGeneratorStatus {
+                                state: state.to_string(),
+                                lambda,
+                                transactions_generated: status.transactions_generated,
+                                last_transaction: status.last_transaction.map(|h| h.to_string()),
+                            });
                         }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/peers" => {
+                            let v: Vec<PeerStatus> = network
+                                .peers()
+                                .into_iter()
+                                .map(|p| PeerStatus {
+                                    addr: p.addr.to_string(),
+                                    outbound: p.outbound,
+                                    handshake_complete: p.version.is_some(),
+                                    listen_addr: p.version.as_ref().map(|v| v.listen_addr.to_string()),
+                                    version: p.version.as_ref().map(|v| v.version),
+                                    height: p.version.as_ref().map(|v| v.height),
+                                    identity: p.identity.map(|id| id.to_string()),
+                                    score: p.score,
+                                })
+                                .collect();
+                            respond_json!(req, v);
+                        }
+                        "/network/relay-stats" => {
+                            let stats = *network.relay_stats().lock().unwrap();
+                            respond_json!(req, stats);
+                        }
+                        "/blockchain/orphans" => {
+                            let stats = orphan_pool.lock().unwrap().stats();
+                            respond_json!(req, stats);
+                        }
                         "/blockchain/longest-chain" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
                             let v_string: Vec<String> = v.into_iter().map(|h|h.to_string()).collect();
                             respond_json!(req, v_string);
                         }
+                        "/blockchain/longest-chain-work" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let v: Vec<BlockWork> = blockchain
+                                .all_blocks_in_longest_chain()
+                                .into_iter()
+                                .map(|h| BlockWork {
+                                    hash: h.to_string(),
+                                    height: blockchain.height(&h).unwrap(),
+                                    work: block_work(&blockchain.get_header(&h).unwrap().difficulty).to_string(),
+                                    cumulative_work: blockchain.cumulative_work(&h).unwrap().to_string(),
+                                })
+                                .collect();
+                            respond_json!(req, v);
+                        }
                         "/blockchain/longest-chain-tx" => {
                             let blockchain = blockchain.lock().unwrap();
                             let tx_vec = blockchain.all_transactions_in_longest_chain();
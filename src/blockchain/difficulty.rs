@@ -0,0 +1,98 @@
+use crate::types::block::Header;
+use crate::types::hash::H256;
+use crate::types::u256::U256;
+use super::Blockchain;
+
+/// The most a single retarget can change the difficulty by, in either direction.
+const MAX_ADJUSTMENT_FACTOR: u64 = 4;
+
+/// Parameters of the difficulty retargeting rule.
+///
+/// Every `epoch_length` blocks, the target is scaled by how long the previous epoch actually took
+/// compared to `epoch_length` blocks at `block_interval_ms` each.
+#[derive(Debug, Clone, Copy)]
+pub struct RetargetConfig {
+    /// Number of blocks between retargets, 0 disables retargeting
+    pub epoch_length: u64,
+    /// Desired time between blocks, in milliseconds
+    pub block_interval_ms: u64,
+}
+
+impl Default for RetargetConfig {
+    fn default() -> Self {
+        RetargetConfig {
+            epoch_length: 20,
+            block_interval_ms: 1000,
+        }
+    }
+}
+
+/// The expected number of hashes needed to find a block meeting `difficulty`, which is
+/// 2^256 / (difficulty + 1).
+pub fn block_work(difficulty: &H256) -> U256 {
+    let target = U256::from(*difficulty);
+    match target.checked_add(&U256::from(1)) {
+        // 2^256 / (target + 1) == (2^256 - target - 1) / (target + 1) + 1
+        Some(divisor) => target.not().div(&divisor).checked_add(&U256::from(1)).unwrap(),
+        None => U256::from(1),
+    }
+}
+
+impl Blockchain {
+    /// Get the difficulty that a child of `parent` must have
+    pub fn expected_difficulty(&self, parent: &H256) -> H256 {
+        let header = &self.hash_header_map[parent];
+        self.difficulty_after(header, self.height(parent).unwrap())
+            .expect("Ancestors of a block in the blockchain are in the blockchain")
+    }
+
+    /// Get the difficulty that a child of the block with header `parent` at height `parent_height`
+    /// must have. Only the parent's ancestors have to be in the blockchain, not the parent itself,
+    /// `None` if the ones needed are not.
+    pub fn difficulty_after(&self, parent: &Header, parent_height: u64) -> Option<H256> {
+        self.difficulty_after_with(parent, parent_height, |h| self.hash_header_map.get(h))
+    }
+
+    /// Like `difficulty_after`, with the parent's ancestors looked up by `ancestor` instead of in
+    /// the blockchain, for chains of headers whose blocks are not in it yet.
+    pub fn difficulty_after_with<'a>(
+        &self,
+        parent: &Header,
+        parent_height: u64,
+        ancestor: impl Fn(&H256) -> Option<&'a Header>,
+    ) -> Option<H256> {
+        let epoch_length = self.retarget.epoch_length;
+        let height = parent_height + 1;
+        if epoch_length == 0 || !height.is_multiple_of(epoch_length) {
+            return Some(parent.difficulty);
+        }
+        // the genesis timestamp is a constant, so an epoch never starts before block 1
+        let first_height = if height > epoch_length { height - epoch_length } else { 1 };
+        let intervals = parent_height.saturating_sub(first_height);
+        if intervals == 0 {
+            return Some(parent.difficulty);
+        }
+        let mut first = parent.parent;
+        for _ in 0..(intervals - 1) {
+            first = ancestor(&first)?.parent;
+        }
+        let first_timestamp = ancestor(&first)?.timestamp;
+
+        let expected_timespan = intervals * self.retarget.block_interval_ms;
+        let actual_timespan = (parent.timestamp.saturating_sub(first_timestamp) as u64)
+            .max(expected_timespan / MAX_ADJUSTMENT_FACTOR)
+            .min(expected_timespan * MAX_ADJUSTMENT_FACTOR);
+
+        let target = U256::from(parent.difficulty);
+        let new_target = match target.checked_mul_u64(actual_timespan) {
+            Some(t) => t.div_u64(expected_timespan),
+            None => target.div_u64(expected_timespan).checked_mul_u64(actual_timespan).unwrap_or_else(U256::max_value),
+        };
+        Some(new_target.min(U256::from(self.pow_limit())).into())
+    }
+
+    /// Get the easiest allowed difficulty, which is the genesis difficulty
+    pub fn pow_limit(&self) -> H256 {
+        self.hash_header_map[&self.genesis].difficulty
+    }
+}
@@ -1,30 +1,95 @@
+pub mod difficulty;
+pub mod orphan;
+pub mod reorg;
+pub mod store;
+
 use crate::types::hash::{H256, Hashable};
-use crate::types::block::{self, *};
-use std::collections::{HashMap, HashSet};
+use crate::types::block::*;
+use crossbeam::channel::{bounded, Receiver, Sender, TrySendError};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
 use crate::types::merkle::MerkleTree;
 use crate::types::transaction::SignedTransaction;
 use hex_literal::hex;
-use rand::Rng;
-use rand::seq::IteratorRandom;
-use crate::types::address::Address;
+use crate::types::u256::U256;
+use self::difficulty::{block_work, RetargetConfig};
+use self::reorg::Reorg;
+use self::store::{BlockStore, MemoryStore};
+
 
+/// The tip of the longest chain and its height.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TipInfo {
+    pub hash: H256,
+    pub height: u64,
+}
 
 pub struct Blockchain {
     tip: H256,
-    max_len: u128,
-    pub hash_block_map: HashMap<H256, Block>,
+    genesis: H256,
+    retarget: RetargetConfig,
+    store: Box<dyn BlockStore>,
+    hash_header_map: HashMap<H256, Header>,
     hash_len_map: HashMap<H256, u128>,
+    /// Total work of every block from genesis up to and including the block
+    hash_work_map: HashMap<H256, U256>,
+    /// Notified of the new tip whenever it changes
+    tip_subscribers: Vec<Sender<H256>>,
+    /// Copy of the tip for code that must not wait for the blockchain lock, only ever locked to
+    /// read or replace it
+    tip_info: Arc<Mutex<TipInfo>>,
 }
 
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
+        Self::with_store(Box::new(MemoryStore::new())).unwrap()
+    }
+
+    /// Create a blockchain backed by `store`. Blocks already in the store are replayed in the
+    /// order they were stored, which recovers the chain lengths and the same tip as before.
+    pub fn with_store(mut store: Box<dyn BlockStore>) -> io::Result<Self> {
+        let genesis = Self::genesis_block();
+        let genesis_hash = genesis.hash();
+        if !store.contains(&genesis_hash) {
+            store.put(&genesis)?;
+        }
+        let blocks = store.blocks()?;
+
+        let mut hash_header_map: HashMap<H256, Header> = HashMap::new();
+        let mut hash_len_map: HashMap<H256, u128> = HashMap::new();
+        hash_header_map.insert(genesis_hash, genesis.header.clone());
+        hash_len_map.insert(genesis_hash, 1);
+        let mut hash_work_map: HashMap<H256, U256> = HashMap::new();
+        hash_work_map.insert(genesis_hash, block_work(&genesis.header.difficulty));
+
+        let mut blockchain = Blockchain {
+            tip: genesis_hash,
+            genesis: genesis_hash,
+            retarget: RetargetConfig::default(),
+            store,
+            hash_header_map,
+            hash_len_map,
+            hash_work_map,
+            tip_subscribers: vec![],
+            tip_info: Arc::new(Mutex::new(TipInfo { hash: genesis_hash, height: 0 })),
+        };
+        for block in blocks.iter() {
+            if block.hash() != genesis_hash {
+                blockchain.index(block);
+            }
+        }
+        Ok(blockchain)
+    }
+
+    fn genesis_block() -> Block {
         let parent_: H256 = [0u8; 32].into();
         let nonce_ = 0u32;
         let difficulty_: H256 =
             hex!("08812818230e0b3b608814e05e61fde06d0df794468a12162f287412df3ec890").into();
         let timestamp_ = 0u128;
-        let mut tx_data: Vec<SignedTransaction> = Vec::new();
+        let tx_data: Vec<SignedTransaction> = Vec::new();
         let merkle_tree = MerkleTree::new(&tx_data);
         let tree_root = merkle_tree.root();
         let header_ = Header {
@@ -34,40 +99,54 @@ impl Blockchain {
             timestamp: timestamp_,
             merkle_root: tree_root,
         };
-        let content_ = Content { data: tx_data };
-        let genesis: Block = Block {
+        let content_ = Content { coinbase: None, data: tx_data };
+        Block {
             header: header_,
             content: content_,
-        };
-
-        let mut tip: H256 = genesis.hash();
-        let mut max_len: u128 = 1;
-        let mut hash_block_map: HashMap<H256, Block> = HashMap::new();
-        let mut hash_len_map: HashMap<H256, u128> = HashMap::new();
-        hash_block_map.insert(tip, genesis.clone());
-        hash_len_map.insert(tip, max_len);
+        }
+    }
 
-        Blockchain {
-            tip,
-            max_len,
-            hash_block_map,
-            hash_len_map,
+    /// Insert a block into blockchain, returns the reorg if the tip changed
+    pub fn insert(&mut self, block: &Block) -> Option<Reorg> {
+        if self.has(block.hash()) {
+            return None;
+        }
+        self.store.put(block).expect("Error writing block to store");
+        let old_tip = self.tip;
+        self.index(block);
+        if self.tip != old_tip {
+            let tip = self.tip;
+            // a pending notification is enough, the subscriber reads the latest tip anyway
+            self.tip_subscribers
+                .retain(|s| !matches!(s.try_send(tip), Err(TrySendError::Disconnected(_))));
+            Some(Reorg::new(self, old_tip, self.tip))
+        } else {
+            None
         }
     }
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
+    /// Update the in-memory indexes (header, chain length, work, tip) for a stored block.
+    /// The tip is the block with the most cumulative work, the first one seen wins a tie.
+    fn index(&mut self, block: &Block) {
         let blk_hash = block.hash();
-        self.hash_block_map.insert(blk_hash, block.clone());
+        self.hash_header_map.insert(blk_hash, block.header.clone());
         let parent = block.get_parent();
         let mut pre_len = 1;
         if self.hash_len_map.contains_key(&parent) {
             pre_len = self.hash_len_map[&parent];
-        } 
+        }
         self.hash_len_map.insert(blk_hash, pre_len + 1);
-        if pre_len + 1 > self.max_len {
+        let pre_work = self.hash_work_map.get(&parent).copied().unwrap_or_else(U256::zero);
+        let work = pre_work
+            .checked_add(&block_work(&block.header.difficulty))
+            .expect("Cumulative work overflow");
+        self.hash_work_map.insert(blk_hash, work);
+        if work > self.hash_work_map[&self.tip] {
             self.tip = blk_hash;
-            self.max_len = pre_len + 1;
+            *self.tip_info.lock().unwrap() = TipInfo {
+                hash: blk_hash,
+                height: pre_len as u64,
+            };
         }
     }
 
@@ -76,34 +155,142 @@ impl Blockchain {
         self.tip
     }
 
+    /// Get notified when the tip changes. Notifications are coalesced: while one is waiting to be
+    /// received, later tip changes are not queued.
+    pub fn subscribe_tip(&mut self) -> Receiver<H256> {
+        let (sender, receiver) = bounded(1);
+        self.tip_subscribers.push(sender);
+        receiver
+    }
+
+    /// Get the tip as it is kept up to date outside of the blockchain, which can be read without
+    /// holding the blockchain lock
+    pub fn tip_info(&self) -> Arc<Mutex<TipInfo>> {
+        Arc::clone(&self.tip_info)
+    }
+
+    /// Set the difficulty retargeting rule used by `expected_difficulty`
+    pub fn set_retarget_config(&mut self, config: RetargetConfig) {
+        self.retarget = config;
+    }
+
+    /// Get the height of a block, the genesis block being at height 0
+    pub fn height(&self, hash: &H256) -> Option<u64> {
+        self.hash_len_map.get(hash).map(|len| (len - 1) as u64)
+    }
+
+    /// Get the total work of the chain ending at a block
+    pub fn cumulative_work(&self, hash: &H256) -> Option<U256> {
+        self.hash_work_map.get(hash).copied()
+    }
+
+    /// Get the genesis block's hash
+    pub fn genesis(&self) -> H256 {
+        self.genesis
+    }
+
     pub fn has(&self, key: H256) -> bool {
-        return self.hash_block_map.contains_key(&key);
+        self.store.contains(&key)
     }
 
-    pub fn all_transactions_in_longest_chain(&self) -> Vec<Vec<SignedTransaction>> {
-        let mut p = self.tip;
-        let mut tx_vec = Vec::new();
-        for i in 0..self.max_len {
-            tx_vec.push(self.hash_block_map[&p].get_tx());
-            p = self.hash_block_map[&p].get_parent();
+    /// Get a block by its hash
+    pub fn get(&self, hash: &H256) -> Option<Block> {
+        self.store.get(hash)
+    }
+
+    /// Get a block's header by its hash, without going to the store
+    pub fn get_header(&self, hash: &H256) -> Option<&Header> {
+        self.hash_header_map.get(hash)
+    }
+
+    /// Get the latest block that both `a` and `b` descend from (or are)
+    pub fn common_ancestor(&self, a: H256, b: H256) -> H256 {
+        let (mut a, mut b) = (a, b);
+        while self.hash_len_map[&a] > self.hash_len_map[&b] {
+            a = self.hash_header_map[&a].parent;
+        }
+        while self.hash_len_map[&b] > self.hash_len_map[&a] {
+            b = self.hash_header_map[&b].parent;
+        }
+        while a != b {
+            a = self.hash_header_map[&a].parent;
+            b = self.hash_header_map[&b].parent;
+        }
+        a
+    }
+
+    /// Get the hashes of the blocks after `ancestor` up to and including `descendant`, ordered
+    /// from the ancestor's child to the descendant
+    pub fn path_from_ancestor(&self, ancestor: H256, descendant: H256) -> Vec<H256> {
+        let mut path: Vec<H256> = Vec::new();
+        let mut p = descendant;
+        while p != ancestor {
+            path.push(p);
+            p = self.hash_header_map[&p].parent;
         }
-        tx_vec.reverse();
-        tx_vec
+        path.reverse();
+        path
+    }
+
+    /// Get all blocks in the order they were inserted, so every block comes after its parent
+    pub fn all_blocks(&self) -> Vec<Block> {
+        self.store.blocks().expect("Error reading blocks from store")
     }
 
+    pub fn all_transactions_in_longest_chain(&self) -> Vec<Vec<SignedTransaction>> {
+        self.all_blocks_in_longest_chain()
+            .iter()
+            .map(|h| self.store.get(h).expect("Block missing from store").get_tx())
+            .collect()
+    }
 
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
-        // unimplemented!()
         let mut chain: Vec<H256> = Vec::new();
         let mut p = self.tip();
-        for i in 0..self.max_len{
+        for _ in 0..self.hash_len_map[&self.tip] {
             chain.push(p);
-            p = self.hash_block_map[&p].get_parent();
+            p = self.hash_header_map[&p].parent;
         }
         chain.reverse();
         chain
     }
+
+    /// Get hashes of the longest chain for a peer to find where its chain forks from ours: the
+    /// last 10 blocks from the tip, then exponentially further apart, ending with genesis
+    pub fn locator(&self) -> Vec<H256> {
+        let chain = self.all_blocks_in_longest_chain();
+        let mut locator = vec![];
+        let mut step = 1;
+        let mut height = chain.len() - 1;
+        while height > 0 {
+            locator.push(chain[height]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator.push(self.genesis);
+        locator
+    }
+
+    /// Get up to `max` headers of the longest chain, following the first `locator` hash that is
+    /// in the longest chain, or following genesis if none is
+    pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Header> {
+        let chain = self.all_blocks_in_longest_chain();
+        let start = locator
+            .iter()
+            .find_map(|h| {
+                let height = self.height(h)? as usize;
+                if chain.get(height) == Some(h) {
+                    Some(height + 1)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(1);
+        chain.iter().skip(start).take(max).map(|h| self.hash_header_map[h].clone()).collect()
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -124,6 +311,143 @@ mod tests {
 
     }
 
+    #[test]
+    fn reorg_to_heavier_fork() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_a1 = generate_random_block(&genesis_hash);
+        let block_a2 = generate_random_block(&block_a1.hash());
+        let block_b1 = generate_random_block(&genesis_hash);
+        let block_b2 = generate_random_block(&block_b1.hash());
+        let block_b3 = generate_random_block(&block_b2.hash());
+        assert!(blockchain.insert(&block_a1).is_some());
+        assert!(blockchain.insert(&block_a2).is_some());
+        assert!(blockchain.insert(&block_b1).is_none());
+        assert!(blockchain.insert(&block_b2).is_none());
+        let reorg = blockchain.insert(&block_b3).unwrap();
+        assert_eq!(reorg.old_tip, block_a2.hash());
+        assert_eq!(reorg.new_tip, block_b3.hash());
+        assert_eq!(reorg.common_ancestor, genesis_hash);
+        assert_eq!(reorg.disconnected, vec![block_a1.hash(), block_a2.hash()]);
+        assert_eq!(reorg.connected, vec![block_b1.hash(), block_b2.hash(), block_b3.hash()]);
+    }
+
+    #[test]
+    fn notify_tip_change() {
+        let mut blockchain = Blockchain::new();
+        let tip_chan = blockchain.subscribe_tip();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_1);
+        // coalesced with the notification still waiting
+        blockchain.insert(&block_2);
+        assert_eq!(tip_chan.try_recv(), Ok(block_1.hash()));
+        assert!(tip_chan.try_recv().is_err());
+
+        blockchain.insert(&generate_random_block(&genesis_hash));
+        assert!(tip_chan.try_recv().is_err());
+        drop(tip_chan);
+        blockchain.insert(&generate_random_block(&block_2.hash()));
+        assert!(blockchain.tip_subscribers.is_empty());
+    }
+
+    #[test]
+    fn headers_from_locator() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut chain = vec![genesis_hash];
+        for _ in 0..30 {
+            let block = generate_random_block(chain.last().unwrap());
+            blockchain.insert(&block);
+            chain.push(block.hash());
+        }
+        let locator = blockchain.locator();
+        assert_eq!(&locator[..10], &chain.iter().rev().take(10).copied().collect::<Vec<H256>>()[..]);
+        assert_eq!(*locator.last().unwrap(), genesis_hash);
+
+        // a peer that forked at height 5
+        let fork = generate_random_block(&chain[5]);
+        let theirs = vec![fork.hash(), chain[5], genesis_hash];
+        let headers = blockchain.headers_after(&theirs, 3);
+        let hashes: Vec<H256> = headers.iter().map(|h| h.hash()).collect();
+        assert_eq!(hashes, chain[6..9].to_vec());
+        assert!(blockchain.headers_after(&[*chain.last().unwrap()], 3).is_empty());
+    }
+
+    #[test]
+    fn retarget_difficulty() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_retarget_config(RetargetConfig { epoch_length: 4, block_interval_ms: 1000 });
+        let difficulty: H256 = hex!("0000ffff00000000000000000000000000000000000000000000000000000000").into();
+        let mut parent = blockchain.tip();
+        for timestamp in [1000, 1500, 2000].iter() {
+            assert_eq!(blockchain.expected_difficulty(&parent), blockchain.get_header(&parent).unwrap().difficulty);
+            let mut block = generate_random_block(&parent);
+            block.header.difficulty = difficulty;
+            block.header.timestamp = *timestamp;
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        // the last two intervals took 1000ms instead of 2000ms, so the target halves
+        let expected: H256 = hex!("00007fff80000000000000000000000000000000000000000000000000000000").into();
+        assert_eq!(blockchain.expected_difficulty(&parent), expected);
+    }
+
+    #[test]
+    fn most_work_wins() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_a1 = generate_random_block(&genesis_hash);
+        let block_a2 = generate_random_block(&block_a1.hash());
+        let mut block_b1 = generate_random_block(&genesis_hash);
+        block_b1.header.difficulty = hex!("0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        let block_c1 = generate_random_block(&genesis_hash);
+        let block_c2 = generate_random_block(&block_c1.hash());
+        blockchain.insert(&block_a1);
+        blockchain.insert(&block_a2);
+        // a tie keeps the first seen tip
+        blockchain.insert(&block_c1);
+        blockchain.insert(&block_c2);
+        assert_eq!(blockchain.tip(), block_a2.hash());
+        let tip_info = blockchain.tip_info();
+        assert_eq!(*tip_info.lock().unwrap(), TipInfo { hash: block_a2.hash(), height: 2 });
+        // one block at a much harder difficulty outweighs two easy ones
+        blockchain.insert(&block_b1);
+        assert_eq!(blockchain.tip(), block_b1.hash());
+        assert_eq!(*tip_info.lock().unwrap(), TipInfo { hash: block_b1.hash(), height: 1 });
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis_hash, block_b1.hash()]);
+        assert_eq!(
+            blockchain.cumulative_work(&block_b1.hash()).unwrap(),
+            blockchain.cumulative_work(&genesis_hash).unwrap().checked_add(&U256::from(1 << 16)).unwrap()
+        );
+    }
+
+    #[test]
+    fn recover_from_file_store() {
+        let path = std::env::temp_dir().join(format!("blocks-{}.dat", crate::types::hash::generate_random_hash()));
+        let mut blockchain = Blockchain::with_store(Box::new(store::FileStore::open(&path).unwrap())).unwrap();
+        let block_1 = generate_random_block(&blockchain.tip());
+        let block_2 = generate_random_block(&block_1.hash());
+        let fork = generate_random_block(&blockchain.genesis());
+        blockchain.insert(&block_1);
+        blockchain.insert(&block_2);
+        blockchain.insert(&fork);
+        let longest_chain = blockchain.all_blocks_in_longest_chain();
+        drop(blockchain);
+
+        // a torn write at the end of the log is dropped on recovery
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, &[0, 0, 1, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let blockchain = Blockchain::with_store(Box::new(store::FileStore::open(&path).unwrap())).unwrap();
+        assert_eq!(blockchain.tip(), block_2.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), longest_chain);
+        assert!(blockchain.has(fork.hash()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
 
 
 }
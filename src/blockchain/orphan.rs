@@ -0,0 +1,257 @@
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a missing parent before asking for it again.
+const PARENT_REQUEST_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Limits on what the orphan pool holds.
+#[derive(Debug, Clone, Copy)]
+pub struct OrphanConfig {
+    pub max_blocks: usize,
+    /// Total serialized size of the blocks
+    pub max_bytes: usize,
+    /// How long a block may wait for its parent before it is dropped
+    pub expiry: Duration,
+}
+
+impl Default for OrphanConfig {
+    fn default() -> Self {
+        OrphanConfig {
+            max_blocks: 200,
+            max_bytes: 32 * 1024 * 1024,
+            expiry: Duration::from_secs(20 * 60),
+        }
+    }
+}
+
+/// Counters of the orphan pool, reported over the API.
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct OrphanStats {
+    pub orphans: usize,
+    pub bytes: usize,
+    /// Parents missing from both the blockchain and the pool
+    pub missing_parents: usize,
+    /// Orphans dropped to stay within the limits
+    pub evicted: u64,
+    /// Orphans dropped because their parent never arrived
+    pub expired: u64,
+    /// Orphans received again while already in the pool
+    pub duplicates: u64,
+}
+
+#[derive(Debug)]
+struct Orphan {
+    block: Block,
+    size: usize,
+    received: Instant,
+    /// The peer that sent it, which should have the parent
+    from: SocketAddr,
+}
+
+/// Blocks whose parent is not in the blockchain yet, kept until the parent arrives.
+///
+/// Blocks only get here after their proof of work is checked, so filling the pool costs work.
+/// When full, the oldest orphans are evicted first.
+#[derive(Debug, Default)]
+pub struct OrphanPool {
+    config: OrphanConfig,
+    orphans: HashMap<H256, Orphan>,
+    /// Orphans by parent hash
+    children: HashMap<H256, Vec<H256>>,
+    /// Orphans in the order they arrived, with their arrival time to skip entries removed since
+    order: VecDeque<(H256, Instant)>,
+    /// Missing parents, and when they were last requested
+    requested: HashMap<H256, Instant>,
+    stats: OrphanStats,
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        Self::with_config(OrphanConfig::default())
+    }
+
+    pub fn with_config(config: OrphanConfig) -> Self {
+        OrphanPool {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    /// Add a block received from `from` whose parent is unknown. Returns false if it is
+    /// already in the pool.
+    pub fn insert(&mut self, block: Block, from: SocketAddr) -> bool {
+        self.insert_at(block, from, Instant::now())
+    }
+
+    fn insert_at(&mut self, block: Block, from: SocketAddr, now: Instant) -> bool {
+        let hash = block.hash();
+        if self.orphans.contains_key(&hash) {
+            self.stats.duplicates += 1;
+            return false;
+        }
+        let size = bincode::serialized_size(&block).unwrap() as usize;
+        self.children.entry(block.header.parent).or_default().push(hash);
+        self.order.push_back((hash, now));
+        self.stats.bytes += size;
+        self.orphans.insert(
+            hash,
+            Orphan {
+                block,
+                size,
+                received: now,
+                from,
+            },
+        );
+        while self.orphans.len() > self.config.max_blocks || self.stats.bytes > self.config.max_bytes {
+            let (oldest, received) = self.order.pop_front().unwrap();
+            if self.orphans.get(&oldest).map(|o| o.received) == Some(received) {
+                self.remove(&oldest);
+                self.stats.evicted += 1;
+            }
+        }
+        true
+    }
+
+    fn remove(&mut self, hash: &H256) -> Option<Block> {
+        let orphan = self.orphans.remove(hash)?;
+        self.stats.bytes -= orphan.size;
+        let parent = orphan.block.header.parent;
+        if let Some(siblings) = self.children.get_mut(&parent) {
+            siblings.retain(|h| h != hash);
+            if siblings.is_empty() {
+                self.children.remove(&parent);
+                self.requested.remove(&parent);
+            }
+        }
+        Some(orphan.block)
+    }
+
+    /// Take out the orphans whose parent is `parent`, once it is in the blockchain
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        let hashes = self.children.remove(parent).unwrap_or_default();
+        self.requested.remove(parent);
+        hashes
+            .iter()
+            .filter_map(|h| {
+                let orphan = self.orphans.remove(h)?;
+                self.stats.bytes -= orphan.size;
+                Some(orphan.block)
+            })
+            .collect()
+    }
+
+    /// Drop the orphans that waited too long, and get the missing parents to ask for: the ones
+    /// never asked for, and the ones asked for more than `PARENT_REQUEST_INTERVAL` ago. Each
+    /// comes with the peer that sent its most recent child.
+    pub fn due_parents(&mut self) -> Vec<(SocketAddr, H256)> {
+        self.due_parents_at(Instant::now())
+    }
+
+    fn due_parents_at(&mut self, now: Instant) -> Vec<(SocketAddr, H256)> {
+        while let Some((hash, received)) = self.order.front().copied() {
+            if now.saturating_duration_since(received) < self.config.expiry {
+                break;
+            }
+            self.order.pop_front();
+            if self.orphans.get(&hash).map(|o| o.received) == Some(received) {
+                self.remove(&hash);
+                self.stats.expired += 1;
+            }
+        }
+
+        let mut due = vec![];
+        for (parent, children) in self.children.iter() {
+            // a parent in the pool is not missing, its own parent is
+            if self.orphans.contains_key(parent) {
+                continue;
+            }
+            let asked = self.requested.get(parent);
+            if asked.is_some_and(|t| now.saturating_duration_since(*t) < PARENT_REQUEST_INTERVAL) {
+                continue;
+            }
+            let newest = children.iter().filter_map(|h| self.orphans.get(h)).max_by_key(|o| o.received);
+            if let Some(child) = newest {
+                due.push((child.from, *parent));
+            }
+        }
+        for (_, parent) in due.iter() {
+            self.requested.insert(*parent, now);
+        }
+        due
+    }
+
+    pub fn stats(&self) -> OrphanStats {
+        let missing_parents = self.children.keys().filter(|p| !self.orphans.contains_key(p)).count();
+        OrphanStats {
+            orphans: self.orphans.len(),
+            missing_parents,
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:6001".parse().unwrap()
+    }
+
+    #[test]
+    fn evict_oldest_and_connect_children() {
+        let mut pool = OrphanPool::with_config(OrphanConfig {
+            max_blocks: 2,
+            ..Default::default()
+        });
+        let missing = generate_random_hash();
+        let first = generate_random_block(&missing);
+        let second = generate_random_block(&first.hash());
+        let third = generate_random_block(&first.hash());
+        assert!(pool.insert(first.clone(), peer()));
+        assert!(!pool.insert(first.clone(), peer()));
+        assert!(pool.insert(second.clone(), peer()));
+        assert!(pool.insert(third.clone(), peer()));
+        assert!(!pool.contains(&first.hash()));
+
+        let stats = pool.stats();
+        assert_eq!((stats.orphans, stats.evicted, stats.duplicates), (2, 1, 1));
+        // with the first one gone, its hash is the missing parent
+        assert_eq!(pool.due_parents(), vec![(peer(), first.hash())]);
+
+        let children = pool.take_children(&first.hash());
+        assert_eq!(children.len(), 2);
+        assert_eq!(pool.stats().orphans, 0);
+        assert_eq!(pool.stats().bytes, 0);
+    }
+
+    #[test]
+    fn expire_and_request_parents_again() {
+        let mut pool = OrphanPool::new();
+        let start = Instant::now();
+        let missing = generate_random_hash();
+        let first = generate_random_block(&missing);
+        let second = generate_random_block(&first.hash());
+        pool.insert_at(first, peer(), start);
+        pool.insert_at(second, peer(), start);
+
+        // only the root of the chain of orphans is missing
+        assert_eq!(pool.due_parents_at(start), vec![(peer(), missing)]);
+        assert!(pool.due_parents_at(start + PARENT_REQUEST_INTERVAL / 2).is_empty());
+        assert_eq!(pool.due_parents_at(start + PARENT_REQUEST_INTERVAL), vec![(peer(), missing)]);
+
+        assert!(pool.due_parents_at(start + pool.config.expiry).is_empty());
+        let stats = pool.stats();
+        assert_eq!((stats.orphans, stats.expired, stats.missing_parents), (0, 2, 0));
+    }
+}
@@ -0,0 +1,85 @@
+use crate::types::hash::{H256, Hashable};
+use crate::types::mempool::Mempool;
+use crate::types::state::StatePerBlock;
+use crate::types::transaction::SignedTransaction;
+use super::Blockchain;
+use log::info;
+use std::collections::HashSet;
+
+/// A switch of the longest chain from the old tip to the new tip.
+///
+/// Extending the longest chain by one block is the trivial case, with no disconnected blocks and
+/// only the new block connected.
+#[derive(Debug, Clone)]
+pub struct Reorg {
+    pub old_tip: H256,
+    pub new_tip: H256,
+    pub common_ancestor: H256,
+    /// Blocks that left the longest chain, ordered from the common ancestor to the old tip
+    pub disconnected: Vec<H256>,
+    /// Blocks that joined the longest chain, ordered from the common ancestor to the new tip
+    pub connected: Vec<H256>,
+}
+
+impl Reorg {
+    pub fn new(blockchain: &Blockchain, old_tip: H256, new_tip: H256) -> Self {
+        let common_ancestor = blockchain.common_ancestor(old_tip, new_tip);
+        Reorg {
+            old_tip,
+            new_tip,
+            common_ancestor,
+            disconnected: blockchain.path_from_ancestor(common_ancestor, old_tip),
+            connected: blockchain.path_from_ancestor(common_ancestor, new_tip),
+        }
+    }
+
+    /// Whether some blocks were taken out of the longest chain
+    pub fn is_fork_switch(&self) -> bool {
+        !self.disconnected.is_empty()
+    }
+
+    /// Bring the mempool in line with the new longest chain: transactions confirmed by the
+    /// connected blocks are evicted, and transactions of the disconnected blocks that are not in
+    /// the new branch are put back, as long as they still apply on top of the new tip's state.
+    pub fn update_mempool(&self, blockchain: &Blockchain, mempool: &mut Mempool, state_per_block: &StatePerBlock) {
+        let mut confirmed: HashSet<H256> = HashSet::new();
+        for blk_hash in self.connected.iter() {
+            let block = blockchain.get(blk_hash).expect("Connected block missing from blockchain");
+            for tx in block.content.data.iter() {
+                confirmed.insert(tx.hash());
+                mempool.remove(tx);
+            }
+        }
+        // transactions that conflict with the connected ones, by reusing their nonces, are dropped,
+        // and so are the ones that waited too long
+        mempool.prune(&state_per_block.hash_state_map[&self.new_tip]);
+        mempool.expire();
+        if !self.is_fork_switch() {
+            return;
+        }
+
+        let mut state = state_per_block.hash_state_map[&self.new_tip].clone();
+        // transactions already waiting in the mempool keep their place, then the abandoned ones
+        // are replayed in their original order
+        let mut abandoned: Vec<SignedTransaction> = vec![];
+        for blk_hash in self.disconnected.iter() {
+            let block = blockchain.get(blk_hash).expect("Disconnected block missing from blockchain");
+            abandoned.extend(block.content.data.into_iter().filter(|tx| !confirmed.contains(&tx.hash())));
+        }
+        let mut reinjected = 0;
+        for tx in abandoned.iter() {
+            if state.update(tx) && mempool.insert(tx).is_ok() {
+                reinjected += 1;
+            }
+        }
+        info!(
+            "Reorg from {} to {} at {}: {} blocks disconnected, {} connected, {} transactions returned to mempool",
+            self.old_tip,
+            self.new_tip,
+            self.common_ancestor,
+            self.disconnected.len(),
+            self.connected.len(),
+            reinjected
+        );
+    }
+}
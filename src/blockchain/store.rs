@@ -0,0 +1,199 @@
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use ring::digest::{digest, SHA256};
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use log::warn;
+
+/// Storage backend for the blocks of a `Blockchain`.
+pub trait BlockStore: Send {
+    /// Persist a block. Storing a block that is already present is a no-op.
+    fn put(&mut self, block: &Block) -> io::Result<()>;
+
+    /// Get the block with the given hash, if stored.
+    fn get(&self, hash: &H256) -> Option<Block>;
+
+    fn contains(&self, hash: &H256) -> bool;
+
+    /// All stored blocks, in the order they were put.
+    fn blocks(&self) -> io::Result<Vec<Block>>;
+}
+
+/// Keeps every block in memory, nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    hash_block_map: HashMap<H256, Block>,
+    order: Vec<H256>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl BlockStore for MemoryStore {
+    fn put(&mut self, block: &Block) -> io::Result<()> {
+        let hash = block.hash();
+        if let Entry::Vacant(e) = self.hash_block_map.entry(hash) {
+            e.insert(block.clone());
+            self.order.push(hash);
+        }
+        Ok(())
+    }
+
+    fn get(&self, hash: &H256) -> Option<Block> {
+        self.hash_block_map.get(hash).cloned()
+    }
+
+    fn contains(&self, hash: &H256) -> bool {
+        self.hash_block_map.contains_key(hash)
+    }
+
+    fn blocks(&self) -> io::Result<Vec<Block>> {
+        Ok(self.order.iter().map(|h| self.hash_block_map[h].clone()).collect())
+    }
+}
+
+/// Size of the record header: payload length followed by a payload checksum.
+const RECORD_HEADER_LEN: u64 = 8;
+
+/// An append-only block log.
+///
+/// Every block is written as one record `[len: u32 BE][checksum: 4 bytes][bincode block]`, where
+/// the checksum is the first 4 bytes of the SHA256 of the payload. Only an index from block hash
+/// to record offset is kept in memory. On open, the log is scanned from the start and cut off at
+/// the first torn or corrupted record, so a crash in the middle of an append loses at most that
+/// block.
+pub struct FileStore {
+    file: Mutex<File>,
+    index: HashMap<H256, (u64, u32)>,
+    order: Vec<H256>,
+    end: u64,
+}
+
+impl FileStore {
+    /// Open the log at `path`, creating it if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.as_ref())?;
+        let file_len = file.metadata()?.len();
+
+        let mut index = HashMap::new();
+        let mut order = Vec::new();
+        let mut end = 0u64;
+        file.seek(SeekFrom::Start(0))?;
+        loop {
+            let mut record_header = [0u8; RECORD_HEADER_LEN as usize];
+            if end + RECORD_HEADER_LEN > file_len || file.read_exact(&mut record_header).is_err() {
+                break;
+            }
+            let len = u32::from_be_bytes([record_header[0], record_header[1], record_header[2], record_header[3]]);
+            if end + RECORD_HEADER_LEN + len as u64 > file_len {
+                break;
+            }
+            let mut payload = vec![0u8; len as usize];
+            if file.read_exact(&mut payload).is_err() || checksum(&payload) != record_header[4..8] {
+                break;
+            }
+            let block: Block = match bincode::deserialize(&payload) {
+                Ok(b) => b,
+                Err(_) => break,
+            };
+            let hash = block.hash();
+            if let Entry::Vacant(e) = index.entry(hash) {
+                e.insert((end + RECORD_HEADER_LEN, len));
+                order.push(hash);
+            }
+            end += RECORD_HEADER_LEN + len as u64;
+        }
+
+        if end < file_len {
+            warn!(
+                "Block log {} has {} bytes of incomplete data at the end, truncating",
+                path.as_ref().display(),
+                file_len - end
+            );
+            file.set_len(end)?;
+            file.sync_all()?;
+        }
+
+        Ok(FileStore {
+            file: Mutex::new(file),
+            index,
+            order,
+            end,
+        })
+    }
+
+    fn read_at(&self, offset: u64, len: u32) -> io::Result<Block> {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut payload = vec![0u8; len as usize];
+        file.read_exact(&mut payload)?;
+        bincode::deserialize(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl BlockStore for FileStore {
+    fn put(&mut self, block: &Block) -> io::Result<()> {
+        let hash = block.hash();
+        if self.index.contains_key(&hash) {
+            return Ok(());
+        }
+        let payload = bincode::serialize(block).unwrap();
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        record.extend_from_slice(&checksum(&payload));
+        record.extend_from_slice(&payload);
+
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(self.end))?;
+        file.write_all(&record)?;
+        file.sync_data()?;
+        drop(file);
+
+        self.index.insert(hash, (self.end + RECORD_HEADER_LEN, payload.len() as u32));
+        self.order.push(hash);
+        self.end += record.len() as u64;
+        Ok(())
+    }
+
+    fn get(&self, hash: &H256) -> Option<Block> {
+        let (offset, len) = *self.index.get(hash)?;
+        match self.read_at(offset, len) {
+            Ok(block) => Some(block),
+            Err(e) => {
+                warn!("Error reading block {} from log: {}", hash, e);
+                None
+            }
+        }
+    }
+
+    fn contains(&self, hash: &H256) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    fn blocks(&self) -> io::Result<Vec<Block>> {
+        let mut blocks = Vec::with_capacity(self.order.len());
+        for hash in self.order.iter() {
+            let (offset, len) = self.index[hash];
+            blocks.push(self.read_at(offset, len)?);
+        }
+        Ok(blocks)
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let d = digest(&SHA256, payload);
+    let mut res = [0u8; 4];
+    res.copy_from_slice(&d.as_ref()[0..4]);
+    res
+}
@@ -8,20 +8,29 @@ pub mod miner;
 pub mod network;
 pub mod types;
 pub mod tx_gen;
+pub mod validation;
 
 use crate::types::block::Block;
 use crate::types::hash::{Hashable, H256};
 use api::Server as ApiServer;
 use blockchain::Blockchain;
+use blockchain::difficulty::RetargetConfig;
+use blockchain::orphan::OrphanPool;
+use blockchain::store::FileStore;
+use miner::template::BlockLimits;
+use network::addr_book::AddressBook;
+use network::transport::{Encryption, Identity};
+use network::sync::HeaderSync;
 use clap::clap_app;
 use log::{error, info};
 use smol::channel;
-use std::collections::HashMap;
-use crate::types::mempool::Mempool;
+use std::convert::TryInto;
+use crate::types::mempool::{Mempool, MempoolConfig};
+use std::fs;
 use std::net;
+use std::path;
 use std::process;
 use std::sync::{Arc, Mutex};
-use std::thread;
 use std::time;
 use futures::FutureExt;
 use crate::types::state::StatePerBlock;
@@ -35,7 +44,21 @@ fn main() {
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg outbound_peers: --("outbound-peers") [INT] default_value("8") "Sets the number of outbound connections to keep open")
+     (@arg max_message_bytes: --("max-message-bytes") [INT] default_value("16777216") "Sets the size of the largest message accepted from a peer")
+     (@arg encryption: --encryption [MODE] default_value("preferred") "Sets whether connections with peers are encrypted: off, preferred or required")
+     (@arg ban_duration: --("ban-duration") [SECS] default_value("86400") "Sets how long a misbehaving peer is banned")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory to persist the blockchain in, keeps it in memory if not given")
+     (@arg difficulty_epoch: --("difficulty-epoch") [INT] default_value("20") "Sets the number of blocks between difficulty retargets, 0 disables retargeting")
+     (@arg block_interval: --("block-interval") [MS] default_value("1000") "Sets the target time between blocks in milliseconds")
+     (@arg block_max_txs: --("block-max-txs") [INT] default_value("500") "Sets the maximum number of transactions in a mined block")
+     (@arg block_max_bytes: --("block-max-bytes") [INT] default_value("262144") "Sets the maximum total size of the transactions in a mined block")
+     (@arg mempool_max_txs: --("mempool-max-txs") [INT] default_value("5000") "Sets the maximum number of transactions in the mempool")
+     (@arg mempool_max_bytes: --("mempool-max-bytes") [INT] default_value("8388608") "Sets the maximum total size of the transactions in the mempool")
+     (@arg mempool_expiry: --("mempool-expiry") [SECS] default_value("1800") "Sets how long a transaction may wait in the mempool before it is dropped")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of threads searching for block nonces")
+     (@arg miner_address: --("miner-address") [ADDR] "Sets the hex address that receives block rewards, the ICO account if not given")
     )
     .get_matches();
 
@@ -44,10 +67,80 @@ fn main() {
     stderrlog::new().verbosity(verbosity).init().unwrap();
 
     // init
-    let blockchain: Arc<Mutex<Blockchain>> = Arc::new(Mutex::new(Blockchain::new()));
-    let orphan_buffer: Arc<Mutex<HashMap<H256, Vec<Block>>>> = Arc::new(Mutex::new(HashMap::new()));
-    let mempool: Arc<Mutex<Mempool>> = Arc::new(Mutex::new(Mempool::new()));
-    let state_per_block = Arc::new(Mutex::new(StatePerBlock::new(blockchain.lock().unwrap().tip())));
+    let mut blockchain = match matches.value_of("data_dir") {
+        Some(dir) => {
+            let dir = path::Path::new(dir);
+            let store = fs::create_dir_all(dir)
+                .and_then(|_| FileStore::open(dir.join("blocks.dat")))
+                .unwrap_or_else(|e| {
+                    error!("Error opening block store in {}: {}", dir.display(), e);
+                    process::exit(1);
+                });
+            Blockchain::with_store(Box::new(store)).unwrap_or_else(|e| {
+                error!("Error loading blockchain from {}: {}", dir.display(), e);
+                process::exit(1);
+            })
+        }
+        None => Blockchain::new(),
+    };
+    let epoch_length = matches
+        .value_of("difficulty_epoch")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing difficulty epoch: {}", e);
+            process::exit(1);
+        });
+    let block_interval_ms = matches
+        .value_of("block_interval")
+        .unwrap()
+        .parse::<u64>()
+        .ok()
+        .filter(|i| *i > 0)
+        .unwrap_or_else(|| {
+            error!("Error parsing block interval: must be a positive integer");
+            process::exit(1);
+        });
+    blockchain.set_retarget_config(RetargetConfig { epoch_length, block_interval_ms });
+    // recompute the state after every stored block, parents are always stored before children
+    let mut state_per_block = StatePerBlock::new(blockchain.genesis());
+    for block in blockchain.all_blocks().iter().skip(1) {
+        state_per_block.update(block);
+    }
+    info!("Loaded blockchain with tip {}", blockchain.tip());
+    let blockchain: Arc<Mutex<Blockchain>> = Arc::new(Mutex::new(blockchain));
+    let orphan_pool = Arc::new(Mutex::new(OrphanPool::new()));
+    let mempool_max_txs = matches
+        .value_of("mempool_max_txs")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool max transactions: {}", e);
+            process::exit(1);
+        });
+    let mempool_max_bytes = matches
+        .value_of("mempool_max_bytes")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool max bytes: {}", e);
+            process::exit(1);
+        });
+    let mempool_expiry = matches
+        .value_of("mempool_expiry")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool expiry: {}", e);
+            process::exit(1);
+        });
+    let mempool_config = MempoolConfig {
+        max_txs: mempool_max_txs,
+        max_bytes: mempool_max_bytes,
+        expiry: time::Duration::from_secs(mempool_expiry),
+    };
+    let mempool: Arc<Mutex<Mempool>> = Arc::new(Mutex::new(Mempool::with_config(mempool_config)));
+    let state_per_block = Arc::new(Mutex::new(state_per_block));
 
     // parse p2p server address
     let p2p_addr = matches
@@ -69,11 +162,77 @@ fn main() {
             process::exit(1);
         });
 
+    // load the known peers, and add the ones to connect to at start
+    let mut addr_book = match matches.value_of("data_dir") {
+        Some(dir) => AddressBook::open(path::Path::new(dir).join("peers.json")).unwrap_or_else(|e| {
+            error!("Error loading address book from {}: {}", dir, e);
+            process::exit(1);
+        }),
+        None => AddressBook::new(),
+    };
+    if let Some(known_peers) = matches.values_of("known_peer") {
+        for peer in known_peers {
+            match peer.parse::<net::SocketAddr>() {
+                Ok(addr) => addr_book.pin(addr),
+                Err(e) => error!("Error parsing peer address {}: {}", peer, e),
+            }
+        }
+    }
+    let addr_book = Arc::new(Mutex::new(addr_book));
+    let outbound_peers = matches
+        .value_of("outbound_peers")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing outbound peers: {}", e);
+            process::exit(1);
+        });
+    let ban_duration = matches
+        .value_of("ban_duration")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban duration: {}", e);
+            process::exit(1);
+        });
+    let max_message_bytes = matches
+        .value_of("max_message_bytes")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max message bytes: {}", e);
+            process::exit(1);
+        });
+    let encryption = matches
+        .value_of("encryption")
+        .unwrap()
+        .parse::<Encryption>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing encryption: {}", e);
+            process::exit(1);
+        });
+    let server_config = network::server::Config {
+        outbound_peers,
+        ban_duration: time::Duration::from_secs(ban_duration),
+        max_message_bytes,
+        encryption,
+        ..Default::default()
+    };
+    // keep the identity of the node across restarts when there is somewhere to store it
+    let identity = match matches.value_of("data_dir") {
+        Some(dir) => Identity::open(path::Path::new(dir).join("identity.pk8")).unwrap_or_else(|e| {
+            error!("Error loading node identity from {}: {}", dir, e);
+            process::exit(1);
+        }),
+        None => Identity::generate(),
+    };
+    info!("Node identity {}", identity.id());
+
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
     // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx, &blockchain, &addr_book, identity, server_config).unwrap();
     server_ctx.start().unwrap();
 
     // start the worker
@@ -85,8 +244,9 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
+    let sync = Arc::new(Mutex::new(HeaderSync::new()));
     let worker_ctx =
-        network::worker::Worker::new(p2p_workers, msg_rx, &server, &blockchain, &orphan_buffer, &mempool, &state_per_block);
+        network::worker::Worker::new(p2p_workers, msg_rx, &server, &blockchain, &orphan_pool, &mempool, &state_per_block, &sync);
     worker_ctx.start();
 
 
@@ -98,46 +258,54 @@ fn main() {
 
 
     // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &state_per_block);
-    miner_ctx.start();
-    miner_worker_ctx.start();
-
-    // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
-                    }
-                }
-            }
+    let max_txs = matches
+        .value_of("block_max_txs")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block max transactions: {}", e);
+            process::exit(1);
+        });
+    let max_bytes = matches
+        .value_of("block_max_bytes")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block max bytes: {}", e);
+            process::exit(1);
+        });
+    let threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .ok()
+        .filter(|i| *i > 0)
+        .unwrap_or_else(|| {
+            error!("Error parsing miner threads: must be a positive integer");
+            process::exit(1);
         });
+    let mut miner_config = miner::Config {
+        block_limits: BlockLimits { max_txs, max_bytes },
+        threads,
+        ..Default::default()
+    };
+    if let Some(addr) = matches.value_of("miner_address") {
+        let bytes: [u8; 20] = hex::decode(addr)
+            .ok()
+            .and_then(|b| b.try_into().ok())
+            .unwrap_or_else(|| {
+                error!("Error parsing miner address: must be 20 bytes in hex");
+                process::exit(1);
+            });
+        miner_config.address = bytes.into();
     }
+    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, &state_per_block, miner_config);
+    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &miner, &blockchain, &mempool, &state_per_block);
+    miner_ctx.start();
+    miner_worker_ctx.start();
 
     // start the API server
-    ApiServer::start(api_addr, &miner, &server, &blockchain, &tx_handler, &mempool, &state_per_block);
+    ApiServer::start(api_addr, &miner, &server, &blockchain, &orphan_pool, &tx_handler, &mempool, &state_per_block);
 
     loop {
         std::thread::park();
@@ -1,25 +1,41 @@
+pub mod search;
+pub mod template;
 pub mod worker;
 use crate::blockchain::{self, *};
 use crate::types::block::{Block, Content, Header};
-use crate::types::merkle::MerkleTree;
+use crate::types::state::{State, StatePerBlock};
+use self::search::{Job, Searcher};
+use self::template::{BlockLimits, BlockTemplate};
+use crate::validation::median_time_past;
 use rand::Rng;
 use std::time::{SystemTime, UNIX_EPOCH};
-use crate::types::transaction::SignedTransaction;
+use crate::types::address::Address;
+use crate::types::key_pair;
+use crate::types::transaction::{block_subsidy, Coinbase};
+use ring::signature::KeyPair;
 use crate::types::mempool::Mempool;
 use log::info;
 use crate::types::hash::{Hashable, H256};
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time;
+use std::time::{self, Instant};
+
+/// How long to wait for a solution before checking the control channel again.
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(10);
+/// How often the hash rate is measured.
+const HASHRATE_WINDOW: time::Duration = time::Duration::from_secs(1);
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Update,     // update the block in mining, it may due to new blockchain tip or new transaction
+    Pause,
     Exit,
 }
 
-enum OperatingState {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperatingState {
     Paused,
     Run(u64),
     ShutDown,
@@ -32,21 +48,76 @@ pub struct Context {
     finished_block_chan: Sender<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    state_per_block: Arc<Mutex<StatePerBlock>>,
+    config: Config,
+    /// Notified when the blockchain tip changes
+    tip_chan: Receiver<H256>,
+    /// Notified when a transaction enters the mempool
+    new_tx_chan: Receiver<H256>,
+    /// Generation of the job the search threads should be working on
+    generation: Arc<AtomicU64>,
+    /// Hashes tried by all search threads so far
+    hashes: Arc<AtomicU64>,
+    status: Arc<Mutex<Status>>,
+}
+
+/// Miner settings.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub block_limits: BlockLimits,
+    /// Receiver of the block rewards
+    pub address: Address,
+    /// Number of threads searching for nonces
+    pub threads: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            block_limits: BlockLimits::default(),
+            address: Address::from_public_key_bytes(key_pair::ico().public_key().as_ref()),
+            threads: 1,
+        }
+    }
+}
+
+/// What the miner is doing, as reported by the API.
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub state: OperatingState,
+    pub threads: usize,
+    /// Hashes per second over the last measurement window
+    pub hashrate: f64,
+    pub blocks_mined: u64,
+    /// Mined blocks that did not become the tip when inserted
+    pub stale_blocks: u64,
+    pub last_block: Option<H256>,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    status: Arc<Mutex<Status>>,
 }
 
 //double check
 pub fn new(
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
+    state_per_block: &Arc<Mutex<StatePerBlock>>,
+    config: Config,
 ) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
+    let status = Arc::new(Mutex::new(Status {
+        state: OperatingState::Paused,
+        threads: config.threads,
+        hashrate: 0.0,
+        blocks_mined: 0,
+        stale_blocks: 0,
+        last_block: None,
+    }));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
@@ -54,10 +125,18 @@ pub fn new(
         finished_block_chan: finished_block_sender,
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
+        state_per_block: Arc::clone(state_per_block),
+        config,
+        tip_chan: blockchain.lock().unwrap().subscribe_tip(),
+        new_tx_chan: mempool.lock().unwrap().subscribe(),
+        generation: Arc::new(AtomicU64::new(0)),
+        hashes: Arc::new(AtomicU64::new(0)),
+        status: Arc::clone(&status),
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        status,
     };
 
     (ctx, handle, finished_block_receiver)
@@ -70,47 +149,147 @@ fn test_new() -> (Context, Handle, Receiver<Block>) {
     // This test case expects the miner thread to be able to use its blockchain
     let blockchain = Blockchain::new();
     let mempool = Mempool::new();
+    let state_per_block = StatePerBlock::new(blockchain.genesis());
     let blockchain = Arc::new(Mutex::new(blockchain));
     let mempool = Arc::new(Mutex::new(mempool));
-    return new(&blockchain, &mempool);
+    let state_per_block = Arc::new(Mutex::new(state_per_block));
+    return new(&blockchain, &mempool, &state_per_block, Config::default());
 }
 
+// the signals return false once the miner has shut down
 impl Handle {
-    pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+    pub fn exit(&self) -> bool {
+        self.control_chan.send(ControlSignal::Exit).is_ok()
     }
 
-    pub fn start(&self, lambda: u64) {
+    pub fn start(&self, lambda: u64) -> bool {
         self.control_chan
             .send(ControlSignal::Start(lambda))
-            .unwrap();
+            .is_ok()
+    }
+
+    pub fn pause(&self) -> bool {
+        self.control_chan.send(ControlSignal::Pause).is_ok()
+    }
+
+    pub fn update(&self) -> bool {
+        self.control_chan.send(ControlSignal::Update).is_ok()
     }
 
-    pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+    pub fn state(&self) -> OperatingState {
+        self.status.lock().unwrap().state
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
     }
 }
 
+/// A block being mined, with the state after applying it.
+struct Candidate {
+    block: Block,
+    state: State,
+}
+
 impl Context {
     pub fn start(mut self) {
+        let (solution_sender, solution_receiver) = unbounded();
+        let mut job_senders = vec![];
+        for i in 0..self.config.threads {
+            let (job_sender, job_receiver) = unbounded();
+            Searcher::new(i, self.config.threads, job_receiver, solution_sender.clone(), &self.generation, &self.hashes).start();
+            job_senders.push(job_sender);
+        }
         thread::Builder::new()
             .name("miner".to_string())
             .spawn(move || {
-                self.miner_loop();
+                self.miner_loop(job_senders, solution_receiver);
             })
             .unwrap();
         info!("Miner initialized into paused mode");
     }
 
-    fn miner_loop(&mut self) {
-        // main mining loop
-        let mut blockchain_ = self.blockchain.lock().unwrap();
-        let mut parent_ = blockchain_.tip();
-        //the difficulty of this block = difficulty of parent block.
-        let difficulty_ = blockchain_.hash_block_map[&parent_].header.difficulty;
+    /// Assemble a block on top of `parent` from the ready transactions in the mempool, which may
+    /// be none, paying the subsidy and fees to the configured address. Returns `None` while the
+    /// parent's ancestors needed for the difficulty are not all in the blockchain, as happens when
+    /// the miner is ahead of the miner worker by more than one block.
+    fn build_candidate(&self, parent: H256, parent_header: &Header, parent_height: u64, parent_state: &State) -> Option<Candidate> {
+        let blockchain_ = self.blockchain.lock().unwrap();
+        let difficulty_ = blockchain_.difficulty_after(parent_header, parent_height)?;
+        let min_timestamp_ = median_time_past(&blockchain_, parent_header) + 1;
         drop(blockchain_);
-        drop(difficulty_);
-        drop(parent_);
+        let mempool = self.mempool.lock().unwrap();
+        // the transactions left out stay in the mempool
+        let template_ = BlockTemplate::build(mempool.ready(parent_state), parent_state, &self.config.block_limits);
+        drop(mempool);
+
+        let coinbase_ = Coinbase {
+            receiver: self.config.address,
+            value: block_subsidy(parent_height + 1).saturating_add(template_.fees),
+            height: parent_height + 1,
+        };
+        let mut state_ = template_.state;
+        state_.apply_coinbase(&coinbase_, template_.fees);
+        let timestamp_ = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            .max(min_timestamp_);
+        let content_ = Content { coinbase: Some(coinbase_), data: template_.transactions };
+        let nonce_: u32 = rand::thread_rng().gen();
+        let header_ = Header {
+            parent,
+            nonce: nonce_,
+            difficulty: difficulty_,
+            timestamp: timestamp_,
+            merkle_root: content_.merkle_root(),
+        };
+        Some(Candidate {
+            block: Block { header: header_, content: content_ },
+            state: state_,
+        })
+    }
+
+    /// Measure the hash rate if the window is over
+    fn update_hashrate(&self, window_start: &mut Instant, window_hashes: &mut u64) {
+        let elapsed = window_start.elapsed();
+        if elapsed < HASHRATE_WINDOW {
+            return;
+        }
+        let hashes = self.hashes.load(Ordering::Relaxed);
+        self.status.lock().unwrap().hashrate = (hashes - *window_hashes) as f64 / elapsed.as_secs_f64();
+        *window_start = Instant::now();
+        *window_hashes = hashes;
+    }
+
+    fn set_state(&mut self, state: OperatingState) {
+        self.operating_state = state;
+        self.status.lock().unwrap().state = state;
+    }
+
+    /// Stop the search threads working on the current job
+    fn cancel_job(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the current tip with its header, height and state, to mine on top of
+    fn tip_parent(&self) -> (H256, Header, u64, State) {
+        let blockchain_ = self.blockchain.lock().unwrap();
+        let tip = blockchain_.tip();
+        let header = blockchain_.get_header(&tip).unwrap().clone();
+        let height = blockchain_.height(&tip).unwrap();
+        let state = self.state_per_block.lock().unwrap().hash_state_map[&tip].clone();
+        (tip, header, height, state)
+    }
+
+    fn miner_loop(&mut self, job_senders: Vec<Sender<Arc<Job>>>, solutions: Receiver<(u64, Block)>) {
+        // main mining loop
+        // keep the parent's header and height, the parent may be a block we just mined that is
+        // not in the blockchain yet
+        let (mut parent_, mut parent_header_, mut parent_height_, mut parent_state_) = self.tip_parent();
+        let mut candidate_: Option<Candidate> = None;
+        let mut window_start_ = Instant::now();
+        let mut window_hashes_ = 0;
         loop {
             // check and react to control signals
             match self.operating_state {
@@ -119,19 +298,20 @@ impl Context {
                     match signal {
                         ControlSignal::Exit => {
                             info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
+                            self.set_state(OperatingState::ShutDown);
                         }
                         ControlSignal::Start(i) => {
                             info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
+                            self.set_state(OperatingState::Run(i));
                         }
-                        ControlSignal::Update => {
+                        ControlSignal::Update | ControlSignal::Pause => {
                             // in paused state, don't need to update
                         }
                     };
                     continue;
                 }
                 OperatingState::ShutDown => {
+                    self.cancel_job();
                     return;
                 }
                 _ => match self.control_chan.try_recv() {
@@ -139,15 +319,26 @@ impl Context {
                         match signal {
                             ControlSignal::Exit => {
                                 info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
+                                self.set_state(OperatingState::ShutDown);
                             }
                             ControlSignal::Start(i) => {
                                 info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
+                                self.set_state(OperatingState::Run(i));
+                            }
+                            ControlSignal::Pause => {
+                                info!("Miner paused");
+                                self.set_state(OperatingState::Paused);
+                                self.cancel_job();
+                                candidate_ = None;
                             }
                             ControlSignal::Update => {
-                                //unimplemented!()
-                                parent_ = self.blockchain.lock().unwrap().tip();
+                                let (p, h, height, state) = self.tip_parent();
+                                parent_ = p;
+                                parent_header_ = h;
+                                parent_height_ = height;
+                                parent_state_ = state;
+                                // the block being mined is stale, start over on the new tip
+                                candidate_ = None;
                             }
                         };
                     }
@@ -155,74 +346,74 @@ impl Context {
                     Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                 },
             }
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
-            }
-            let mut signed_tx_ = Vec::<SignedTransaction>::new();
-            let mut mempool = self.mempool.lock().unwrap();
-            if mempool.tx_map.len() >= 10 {
-                for tx_hs in mempool.tx_map.keys() {
-                    signed_tx_.push(mempool.tx_map[&tx_hs].clone());
+            match self.operating_state {
+                OperatingState::ShutDown => {
+                    self.cancel_job();
+                    return;
                 }
-                for tx in signed_tx_.clone() {
-                    mempool.remove(&tx);
+                OperatingState::Paused => continue,
+                OperatingState::Run(_) => {}
+            }
+            if self.tip_chan.try_recv().is_ok() {
+                let blockchain_ = self.blockchain.lock().unwrap();
+                let tip = blockchain_.tip();
+                // our own block becoming the tip, or a tip below the block we just mined, is not
+                // worth switching to
+                let switch = tip != parent_ && blockchain_.height(&tip).unwrap() >= parent_height_;
+                drop(blockchain_);
+                if switch {
+                    let (p, h, height, state) = self.tip_parent();
+                    parent_ = p;
+                    parent_header_ = h;
+                    parent_height_ = height;
+                    parent_state_ = state;
+                    candidate_ = None;
                 }
-                if !signed_tx_.is_empty() {
-                    let mut rng = rand::thread_rng();
-                    let timestamp_ = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis();
-
-                    let merkle_tree_ = MerkleTree::new(&signed_tx_.clone());
-                    let merkle_root_ = merkle_tree_.root();
-                    let nonce_: u32 = rng.gen();
-                    let header_ = Header {
-                        parent: parent_,
-                        nonce: nonce_,
-                        difficulty: difficulty_,
-                        timestamp: timestamp_,
-                        merkle_root: merkle_root_,
-                    };
-                    let content_ = Content { data: signed_tx_.clone() };
-                    let mut block = Block {
-                        header: header_,
-                        content: content_,
-                    };
-
-                    if block.hash() <= difficulty_ {
-                        self.finished_block_chan.send(block.clone()).expect("Send finished block error");
-                        parent_ = block.hash();
+            }
+            if self.new_tx_chan.try_recv().is_ok() {
+                // pick up the new transactions, they may pay more than the ones being mined
+                candidate_ = None;
+            }
+            if candidate_.is_none() {
+                let candidate = match self.build_candidate(parent_, &parent_header_, parent_height_, &parent_state_) {
+                    Some(candidate) => candidate,
+                    None => {
+                        thread::sleep(POLL_INTERVAL);
+                        continue;
                     }
-                    
-                    // loop {
-                    //     block.header.nonce = nonce_;
-                    //     // TODO for student: if block mining finished, you can have something like:
-                    //     if block.hash() <= difficulty_ {
-                    //         match self.finished_block_chan.send(block.clone()) {
-                    //             Ok(()) => {
-                    //                 parent_ = block.hash();
-                    //             }
-                    //             // See: https://doc.rust-lang.org/book/ch12-06-writing-to-stderr-instead-of-stdout.html
-                    //             Err(e) => {
-                    //                 eprintln!("Error: {}", e);
-                    //             }
-                    //         }
-                    //         break;
-                    //     }
-                    // }
+                };
+                let lambda = match self.operating_state {
+                    OperatingState::Run(i) => i,
+                    _ => 0,
+                };
+                // a new generation makes the threads drop the job they are working on
+                let job = Arc::new(Job {
+                    generation: self.generation.fetch_add(1, Ordering::Relaxed) + 1,
+                    block: candidate.block.clone(),
+                    lambda,
+                });
+                for sender in job_senders.iter() {
+                    sender.send(Arc::clone(&job)).expect("Miner search thread detached");
                 }
+                candidate_ = Some(candidate);
             }
-            // TODO for student: actual mining, create a block
-            
-            drop(mempool);
-
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
+            if let Ok((generation, block)) = solutions.recv_timeout(POLL_INTERVAL) {
+                // a solution for a job that was already replaced is stale
+                if generation == self.generation.load(Ordering::Relaxed) {
+                    let candidate = candidate_.take().unwrap();
+                    self.cancel_job();
+                    let mut status = self.status.lock().unwrap();
+                    status.blocks_mined += 1;
+                    status.last_block = Some(block.hash());
+                    drop(status);
+                    self.finished_block_chan.send(block.clone()).expect("Send finished block error");
+                    parent_ = block.hash();
+                    parent_header_ = block.header;
+                    parent_height_ += 1;
+                    parent_state_ = candidate.state;
                 }
             }
+            self.update_hashrate(&mut window_start_, &mut window_hashes_);
         }
     }
 }
@@ -236,7 +427,7 @@ mod test {
 
     #[test]
     #[timeout(60000)]
-    fn miner_three_block() { // may always fail cuz cannot mine empty block
+    fn miner_three_block() {
         let (miner_ctx, miner_handle, finished_block_chan) = super::test_new();
         miner_ctx.start();
         miner_handle.start(0);
@@ -0,0 +1,139 @@
+use crate::types::block::Block;
+use crate::types::hash::Hashable;
+use crossbeam::channel::{Receiver, Sender};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{self, SystemTime, UNIX_EPOCH};
+
+/// Nonces tried between two checks for a newer job.
+const NONCES_PER_ROUND: u64 = 10_000;
+
+/// A block to find a nonce for, shared by all search threads.
+pub struct Job {
+    /// Incremented for every new job, a thread gives up its job once it is out of date
+    pub generation: u64,
+    pub block: Block,
+    /// Microseconds to sleep between rounds, 0 to never sleep
+    pub lambda: u64,
+}
+
+/// One of the threads searching the nonce space. With `threads` searchers, the `u32` nonces are
+/// split into as many contiguous ranges, counted from the job's starting nonce.
+pub struct Searcher {
+    index: u64,
+    threads: u64,
+    jobs: Receiver<Arc<Job>>,
+    solutions: Sender<(u64, Block)>,
+    generation: Arc<AtomicU64>,
+    hashes: Arc<AtomicU64>,
+}
+
+impl Searcher {
+    pub fn new(
+        index: usize,
+        threads: usize,
+        jobs: Receiver<Arc<Job>>,
+        solutions: Sender<(u64, Block)>,
+        generation: &Arc<AtomicU64>,
+        hashes: &Arc<AtomicU64>,
+    ) -> Self {
+        Searcher {
+            index: index as u64,
+            threads: threads as u64,
+            jobs,
+            solutions,
+            generation: Arc::clone(generation),
+            hashes: Arc::clone(hashes),
+        }
+    }
+
+    pub fn start(self) {
+        thread::Builder::new()
+            .name(format!("miner-{}", self.index))
+            .spawn(move || {
+                self.search_loop();
+            })
+            .unwrap();
+    }
+
+    fn search_loop(&self) {
+        // exits once the miner drops its end of the job channel
+        while let Ok(mut job) = self.jobs.recv() {
+            // skip the jobs that were replaced while the previous one was running
+            while let Ok(newer) = self.jobs.try_recv() {
+                job = newer;
+            }
+            self.search(&job);
+        }
+    }
+
+    /// Search this thread's range of nonces until a solution is found or the job is out of date.
+    /// Once the whole range has been tried, the timestamp is moved forward and the range starts over.
+    fn search(&self, job: &Job) {
+        let span = (u32::MAX as u64 + 1) / self.threads;
+        let first = job.block.header.nonce.wrapping_add((self.index * span) as u32);
+        let mut block = job.block.clone();
+        block.header.nonce = first;
+        let mut tried = 0;
+        loop {
+            for _ in 0..NONCES_PER_ROUND {
+                if block.hash() <= block.header.difficulty {
+                    self.hashes.fetch_add(tried % NONCES_PER_ROUND + 1, Ordering::Relaxed);
+                    // the miner may have moved on and dropped the receiver
+                    let _ = self.solutions.send((job.generation, block));
+                    return;
+                }
+                tried += 1;
+                if tried % span == 0 {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+                    block.header.timestamp = now.max(block.header.timestamp + 1);
+                    block.header.nonce = first;
+                } else {
+                    block.header.nonce = block.header.nonce.wrapping_add(1);
+                }
+            }
+            self.hashes.fetch_add(NONCES_PER_ROUND, Ordering::Relaxed);
+            if self.generation.load(Ordering::Relaxed) != job.generation {
+                return;
+            }
+            if job.lambda != 0 {
+                thread::sleep(time::Duration::from_micros(job.lambda));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+    use crossbeam::channel::unbounded;
+
+    #[test]
+    fn threads_share_a_job() {
+        let generation = Arc::new(AtomicU64::new(1));
+        let hashes = Arc::new(AtomicU64::new(0));
+        let (solution_sender, solution_receiver) = unbounded();
+        let mut job_senders = vec![];
+        for i in 0..4 {
+            let (job_sender, job_receiver) = unbounded();
+            Searcher::new(i, 4, job_receiver, solution_sender.clone(), &generation, &hashes).start();
+            job_senders.push(job_sender);
+        }
+        let mut block = generate_random_block(&generate_random_hash());
+        block.header.difficulty = [0x0fu8; 32].into();
+        let job = Arc::new(Job { generation: 1, block, lambda: 0 });
+        for sender in job_senders.iter() {
+            sender.send(Arc::clone(&job)).unwrap();
+        }
+
+        let (found_generation, found) = solution_receiver.recv().unwrap();
+        generation.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(found_generation, 1);
+        assert!(found.hash() <= found.header.difficulty);
+        assert_eq!(found.get_parent(), job.block.get_parent());
+        assert!(hashes.load(Ordering::Relaxed) > 0);
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use crate::types::state::State;
+use crate::types::transaction::SignedTransaction;
+
+/// Upper bounds on the transactions a block carries.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockLimits {
+    pub max_txs: usize,
+    /// Total serialized size of the transactions
+    pub max_bytes: usize,
+}
+
+impl Default for BlockLimits {
+    fn default() -> Self {
+        BlockLimits {
+            max_txs: 500,
+            max_bytes: 256 * 1024,
+        }
+    }
+}
+
+/// The transactions chosen for a block, and the state after applying them.
+pub struct BlockTemplate {
+    pub transactions: Vec<SignedTransaction>,
+    pub state: State,
+    pub fees: u32,
+    pub bytes: usize,
+}
+
+impl BlockTemplate {
+    /// Pick transactions from `sequences`, each a run of one sender's transactions in nonce
+    /// order as given by `Mempool::ready`, that apply on top of `parent_state` and fit in
+    /// `limits`. The next transaction of every sequence competes by fee rate, and a sequence is
+    /// dropped at the first transaction that cannot be added, since the ones after it depend on it.
+    pub fn build(sequences: Vec<Vec<SignedTransaction>>, parent_state: &State, limits: &BlockLimits) -> Self {
+        let mut template = BlockTemplate {
+            transactions: vec![],
+            state: parent_state.clone(),
+            fees: 0,
+            bytes: 0,
+        };
+        let mut queues: Vec<VecDeque<SignedTransaction>> = sequences.into_iter().map(VecDeque::from).collect();
+        while template.transactions.len() < limits.max_txs {
+            let best = queues
+                .iter()
+                .enumerate()
+                .filter_map(|(i, q)| q.front().map(|tx| (i, tx)))
+                .max_by(|(_, a), (_, b)| a.cmp_fee_rate(b))
+                .map(|(i, _)| i);
+            let best = match best {
+                Some(i) => i,
+                None => break,
+            };
+            let tx = queues[best].pop_front().unwrap();
+            let size = tx.size();
+            let fees = template.fees.checked_add(tx.transaction.fee);
+            match fees {
+                Some(fees) if template.bytes + size <= limits.max_bytes && template.state.update(&tx) => {
+                    template.bytes += size;
+                    template.fees = fees;
+                    template.transactions.push(tx);
+                }
+                _ => queues[best].clear(),
+            }
+        }
+        template
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::Address;
+    use crate::types::key_pair;
+    use crate::types::state::StatePerBlock;
+    use crate::types::hash::generate_random_hash;
+    use crate::types::transaction::{sign, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn transfer(key: &Ed25519KeyPair, acc_nonce: u32, fee: u32) -> SignedTransaction {
+        let transaction = Transaction {
+            sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+            acc_nonce,
+            receiver: Address::generate_random_address(),
+            value: 1,
+            fee,
+        };
+        SignedTransaction {
+            signature: sign(&transaction, key).as_ref().to_vec(),
+            public_key: key.public_key().as_ref().to_vec(),
+            transaction,
+        }
+    }
+
+    #[test]
+    fn pick_by_fee_within_limits() {
+        let genesis = generate_random_hash();
+        let mut state = StatePerBlock::new(genesis).hash_state_map[&genesis].clone();
+        let ico = key_pair::ico();
+        let tx_1 = transfer(&ico, 1, 1);
+        let tx_2 = transfer(&ico, 2, 9);
+        let tx_3 = transfer(&ico, 3, 5);
+        let other = transfer(&key_pair::random(), 1, 4);
+        state.state.insert(other.transaction.sender, (0, 1000));
+        let sequences = vec![vec![tx_1.clone(), tx_2, tx_3], vec![other]];
+
+        // the best paying transaction waits for the one whose nonce comes before it
+        let limits = BlockLimits { max_txs: 3, max_bytes: 1024 };
+        let template = BlockTemplate::build(sequences.clone(), &state, &limits);
+        let fees: Vec<u32> = template.transactions.iter().map(|t| t.transaction.fee).collect();
+        assert_eq!(fees, vec![4, 1, 9]);
+        assert_eq!(template.fees, 14);
+
+        let limits = BlockLimits { max_txs: 10, max_bytes: tx_1.size() * 3 - 1 };
+        let template = BlockTemplate::build(sequences, &state, &limits);
+        assert_eq!(template.transactions.len(), 2);
+    }
+}
@@ -1,19 +1,25 @@
 use crate::blockchain::Blockchain;
+use crate::miner::Handle as MinerHandle;
+use crate::network::compact::CompactBlock;
 use crate::network::message::Message;
 use crate::network::server::Handle as ServerHandle;
 use crate::types::block::Block;
 use crate::types::hash::{Hashable, H256};
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::{debug, info};
+use log::{debug, info, warn};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use crate::StatePerBlock;
+use crate::types::mempool::Mempool;
+use crate::validation::validate_block;
 
 #[derive(Clone)]
 pub struct Worker {
     server: ServerHandle,
     finished_block_chan: Receiver<Block>,
+    miner: MinerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
     state_per_block: Arc<Mutex<StatePerBlock>>
 }
 
@@ -21,13 +27,17 @@ impl Worker {
     pub fn new(
         server: &ServerHandle,
         finished_block_chan: Receiver<Block>,
+        miner: &MinerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
         state_per_block: &Arc<Mutex<StatePerBlock>>
     ) -> Self {
         Self {
             server: server.clone(),
             finished_block_chan,
+            miner: miner.clone(),
             blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
             state_per_block: Arc::clone(state_per_block),
         }
     }
@@ -50,13 +60,29 @@ impl Worker {
                 .expect("Receive finished block error");
             // TODO for student: insert this finished block to blockchain, and broadcast this block hash
             let mut blockchain_ = self.blockchain.lock().unwrap();
-            blockchain_.insert(&_block);
+            let mut mempool = self.mempool.lock().unwrap();
             let mut state_per_block = self.state_per_block.lock().unwrap();
+            if let Err(e) = validate_block(&_block, &blockchain_, &state_per_block) {
+                warn!("Mined an invalid block {}: {}", _block.hash(), e);
+                // the miner went on from this block, start it over on the tip
+                self.miner.update();
+                continue;
+            }
+            let reorg = blockchain_.insert(&_block);
+            if blockchain_.tip() != _block.hash() {
+                // another block at the same height got there first, or the tip moved on
+                debug!("Mined block {} did not become the tip", _block.hash());
+                self.miner.status.lock().unwrap().stale_blocks += 1;
+            }
             state_per_block.update(&_block);
+            if let Some(reorg) = reorg {
+                reorg.update_mempool(&blockchain_, &mut mempool, &state_per_block);
+            }
+            drop(state_per_block);
+            drop(mempool);
             drop(blockchain_);
-            let mut v = Vec::new();
-            v.push(_block.hash());
-            self.server.broadcast(Message::NewBlockHashes(v));
+            // peers rebuild the block from their mempools
+            self.server.broadcast(Message::CompactBlock(CompactBlock::new(&_block)));
         }
     }
 }
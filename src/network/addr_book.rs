@@ -0,0 +1,153 @@
+use rand::seq::IteratorRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Most addresses sent in one `Addr` message.
+pub const MAX_ADDRS_PER_MESSAGE: usize = 100;
+/// Most addresses kept, new ones are ignored beyond that.
+const MAX_ADDRESSES: usize = 1000;
+/// An address is forgotten after failing this many connection attempts in a row.
+const MAX_FAILURES: u32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+struct Entry {
+    /// Seconds since the epoch when we were last connected, 0 if never
+    last_seen: u64,
+    /// Connection attempts that failed since the last success
+    failures: u32,
+}
+
+/// Addresses of peers that accept connections, learned from the command line and from other
+/// peers. With a path, the book is loaded from and saved to a JSON file.
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    entries: HashMap<SocketAddr, Entry>,
+    path: Option<PathBuf>,
+    /// Addresses given on the command line, kept however often they fail
+    pinned: HashSet<SocketAddr>,
+    /// Whether there are changes not saved yet
+    dirty: bool,
+}
+
+impl AddressBook {
+    /// Create an address book kept in memory only
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open the address book saved at `path`, or start an empty one there
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let entries = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(AddressBook {
+            entries,
+            path: Some(path),
+            pinned: HashSet::new(),
+            dirty: false,
+        })
+    }
+
+    /// Write the book to its file if it changed
+    pub fn save(&mut self) -> io::Result<()> {
+        if let (Some(path), true) = (&self.path, self.dirty) {
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, serde_json::to_vec(&self.entries)?)?;
+            fs::rename(&tmp, path)?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn add(&mut self, addr: SocketAddr) {
+        if self.entries.len() < MAX_ADDRESSES && !self.entries.contains_key(&addr) {
+            self.entries.insert(addr, Entry::default());
+            self.dirty = true;
+        }
+    }
+
+    /// Add an address that is never forgotten
+    pub fn pin(&mut self, addr: SocketAddr) {
+        self.pinned.insert(addr);
+        self.entries.entry(addr).or_default();
+        self.dirty = true;
+    }
+
+    /// Record a successful connection to `addr`
+    pub fn mark_good(&mut self, addr: SocketAddr) {
+        let last_seen = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.entries.insert(addr, Entry { last_seen, failures: 0 });
+        self.dirty = true;
+    }
+
+    /// Record a failed connection to `addr`, forgetting it after too many
+    pub fn mark_failed(&mut self, addr: SocketAddr) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.failures += 1;
+            if entry.failures >= MAX_FAILURES && !self.pinned.contains(&addr) {
+                self.entries.remove(&addr);
+            }
+            self.dirty = true;
+        }
+    }
+
+    /// Pick an address to connect to that is not in `exclude`, preferring ones that worked before
+    pub fn pick(&self, exclude: &HashSet<SocketAddr>) -> Option<SocketAddr> {
+        let mut rng = rand::thread_rng();
+        let candidates = self.entries.iter().filter(|(addr, _)| !exclude.contains(addr));
+        let (seen, unseen): (Vec<_>, Vec<_>) = candidates.partition(|(_, entry)| entry.last_seen > 0);
+        seen.into_iter()
+            .choose(&mut rng)
+            .or_else(|| unseen.into_iter().choose(&mut rng))
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Get up to `n` random addresses, to share with a peer
+    pub fn sample(&self, n: usize) -> Vec<SocketAddr> {
+        self.entries.keys().copied().choose_multiple(&mut rand::thread_rng(), n)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persist_and_forget_failing() {
+        let dir = std::env::temp_dir().join(format!("addr-book-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("peers.json");
+        let good: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let bad: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+
+        let mut book = AddressBook::open(path.clone()).unwrap();
+        book.add(good);
+        book.add(bad);
+        book.mark_good(good);
+        assert_eq!(book.pick(&HashSet::new()), Some(good));
+        assert_eq!(book.pick(&[good].iter().copied().collect()), Some(bad));
+        for _ in 0..MAX_FAILURES {
+            book.mark_failed(bad);
+        }
+        book.save().unwrap();
+
+        let book = AddressBook::open(path).unwrap();
+        assert_eq!(book.sample(10), vec![good]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use crate::types::block::{Block, Content, Header};
+use crate::types::hash::{H256, Hashable};
+use crate::types::mempool::Mempool;
+use crate::types::transaction::{Coinbase, SignedTransaction};
+use ring::digest::{Context, SHA256};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+/// Short identifier of a transaction within one block.
+pub type ShortId = u64;
+
+/// Get the short identifier of a transaction in the block `block_hash`. Salting with the block
+/// hash keeps a collision from affecting more than one block.
+pub fn short_id(block_hash: &H256, tx_hash: &H256) -> ShortId {
+    let mut ctx = Context::new(&SHA256);
+    ctx.update(block_hash.as_ref());
+    ctx.update(tx_hash.as_ref());
+    u64::from_be_bytes(ctx.finish().as_ref()[..8].try_into().unwrap())
+}
+
+/// A block relayed as its header, coinbase and short transaction identifiers. The receiver
+/// rebuilds the block from the transactions in its mempool and asks for the ones it lacks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: Header,
+    pub coinbase: Option<Coinbase>,
+    pub short_ids: Vec<ShortId>,
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> Self {
+        let hash = block.hash();
+        CompactBlock {
+            header: block.header.clone(),
+            coinbase: block.content.coinbase.clone(),
+            short_ids: block.content.data.iter().map(|t| short_id(&hash, &t.hash())).collect(),
+        }
+    }
+}
+
+impl Hashable for CompactBlock {
+    fn hash(&self) -> H256 {
+        self.header.hash()
+    }
+}
+
+/// A compact block being rebuilt, with the transactions found so far.
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    compact: CompactBlock,
+    txs: Vec<Option<SignedTransaction>>,
+}
+
+impl PartialBlock {
+    /// Fill in the transactions found in `mempool`. A short identifier matching several
+    /// transactions is left missing, the sender resolves it.
+    pub fn new(compact: CompactBlock, mempool: &Mempool) -> Self {
+        let hash = compact.hash();
+        let mut by_short_id: HashMap<ShortId, Option<&SignedTransaction>> = HashMap::new();
+        for (tx_hash, tx) in mempool.iter() {
+            by_short_id
+                .entry(short_id(&hash, tx_hash))
+                .and_modify(|found| *found = None)
+                .or_insert(Some(tx));
+        }
+        let txs = compact
+            .short_ids
+            .iter()
+            .map(|id| by_short_id.get(id).copied().flatten().cloned())
+            .collect();
+        PartialBlock { compact, txs }
+    }
+
+    /// Positions of the transactions still missing
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.txs.len() as u32).filter(|i| self.txs[*i as usize].is_none()).collect()
+    }
+
+    /// Fill in the missing transactions, in order. Returns false, leaving the block unchanged, if
+    /// they are not the ones the short identifiers stand for.
+    pub fn fill(&mut self, txs: Vec<SignedTransaction>) -> bool {
+        let missing = self.missing();
+        let hash = self.compact.hash();
+        if txs.len() != missing.len()
+            || missing.iter().zip(txs.iter()).any(|(i, t)| self.compact.short_ids[*i as usize] != short_id(&hash, &t.hash()))
+        {
+            return false;
+        }
+        for (i, tx) in missing.into_iter().zip(txs) {
+            self.txs[i as usize] = Some(tx);
+        }
+        true
+    }
+
+    /// Get the full block, `None` if transactions are missing or a short identifier collision
+    /// made it different from the block the header commits to
+    pub fn into_block(self) -> Option<Block> {
+        let data = self.txs.into_iter().collect::<Option<Vec<SignedTransaction>>>()?;
+        let block = Block {
+            header: self.compact.header,
+            content: Content {
+                coinbase: self.compact.coinbase,
+                data,
+            },
+        };
+        if block.content.merkle_root() != block.header.merkle_root {
+            return None;
+        }
+        Some(block)
+    }
+}
+
+/// Traffic of received blocks, to compare compact relay with sending full blocks.
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct RelayStats {
+    /// Blocks received in full, in `Blocks` messages
+    pub full_blocks: u64,
+    pub full_bytes: u64,
+    /// Compact blocks received
+    pub compact_blocks: u64,
+    /// Size of the compact blocks and of the missing transactions fetched for them
+    pub compact_bytes: u64,
+    /// Size the compact blocks would have had as full blocks
+    pub reconstructed_bytes: u64,
+    /// Transactions that were not in the mempool and had to be fetched
+    pub missing_txs: u64,
+    /// Compact blocks that could not be rebuilt and were fetched in full
+    pub fallbacks: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::Address;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+    use crate::types::key_pair;
+    use crate::types::transaction::{sign, Transaction};
+    use ring::signature::KeyPair;
+
+    fn transfer(fee: u32) -> SignedTransaction {
+        let key = key_pair::random();
+        let transaction = Transaction {
+            sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+            acc_nonce: 1,
+            receiver: Address::generate_random_address(),
+            value: 1,
+            fee,
+        };
+        SignedTransaction {
+            signature: sign(&transaction, &key).as_ref().to_vec(),
+            public_key: key.public_key().as_ref().to_vec(),
+            transaction,
+        }
+    }
+
+    #[test]
+    fn rebuild_from_mempool() {
+        let mut block = generate_random_block(&generate_random_hash());
+        block.content.data = (1..=3).map(transfer).collect();
+        block.header.merkle_root = block.content.merkle_root();
+        let compact = CompactBlock::new(&block);
+        assert!(bincode::serialize(&compact).unwrap().len() < bincode::serialize(&block).unwrap().len());
+
+        let mut mempool = Mempool::new();
+        mempool.insert(&block.content.data[0]).unwrap();
+        mempool.insert(&block.content.data[2]).unwrap();
+        mempool.insert(&transfer(4)).unwrap();
+        let mut partial = PartialBlock::new(compact, &mempool);
+        assert_eq!(partial.missing(), vec![1]);
+        assert!(partial.clone().into_block().is_none());
+
+        assert!(!partial.fill(vec![transfer(2)]));
+        assert!(partial.fill(vec![block.content.data[1].clone()]));
+        assert!(partial.missing().is_empty());
+        let rebuilt = partial.into_block().unwrap();
+        assert_eq!(rebuilt.hash(), block.hash());
+        assert_eq!(rebuilt.content.merkle_root(), block.header.merkle_root);
+    }
+}
@@ -0,0 +1,181 @@
+use super::peer;
+use crate::types::hash::H256;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Most hashes remembered per peer, the oldest are forgotten first.
+pub const MAX_KNOWN_INVENTORY: usize = 10_000;
+/// How long a peer has to answer a request before it is asked from another peer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the requests are checked for timeouts.
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Most peers remembered as able to answer for one item.
+const MAX_ANNOUNCERS: usize = 8;
+/// Most requests tracked at once, items beyond that are requested without tracking.
+const MAX_REQUESTS: usize = 50_000;
+
+/// Blocks and transactions a peer is known to have, because it announced or sent them, or we
+/// did. Bounded, forgetting the oldest.
+#[derive(Debug)]
+pub struct KnownInventory {
+    hashes: HashSet<H256>,
+    order: VecDeque<H256>,
+    capacity: usize,
+}
+
+impl KnownInventory {
+    pub fn new(capacity: usize) -> Self {
+        KnownInventory {
+            hashes: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn insert(&mut self, hash: H256) {
+        if !self.hashes.insert(hash) {
+            return;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.hashes.remove(&oldest);
+        }
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.hashes.contains(hash)
+    }
+}
+
+#[derive(Debug)]
+struct Request {
+    /// The peer asked, and when
+    peer: SocketAddr,
+    asked: Instant,
+    /// Other peers that announced the item, to ask next
+    announcers: VecDeque<peer::Handle>,
+}
+
+/// Items requested from peers after they announced them, so an item announced by several peers is
+/// only fetched from one at a time.
+#[derive(Debug)]
+pub struct Requests {
+    in_flight: HashMap<H256, Request>,
+    last_check: Instant,
+}
+
+impl Default for Requests {
+    fn default() -> Self {
+        Requests {
+            in_flight: HashMap::new(),
+            last_check: Instant::now(),
+        }
+    }
+}
+
+impl Requests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that `peer` announced `hash`. Returns true if the item should be requested from
+    /// `peer` now, false if it is already requested from another peer, which `peer` then backs up.
+    pub fn announced(&mut self, hash: H256, peer: &peer::Handle) -> bool {
+        if let Some(request) = self.in_flight.get_mut(&hash) {
+            let known = request.peer == *peer.addr() || request.announcers.iter().any(|p| p.addr() == peer.addr());
+            if !known && request.announcers.len() < MAX_ANNOUNCERS {
+                request.announcers.push_back(peer.clone());
+            }
+            return false;
+        }
+        if self.in_flight.len() < MAX_REQUESTS {
+            self.in_flight.insert(
+                hash,
+                Request {
+                    peer: *peer.addr(),
+                    asked: Instant::now(),
+                    announcers: VecDeque::new(),
+                },
+            );
+        }
+        true
+    }
+
+    /// Forget an item once it is received
+    pub fn received(&mut self, hash: &H256) {
+        self.in_flight.remove(hash);
+    }
+
+    /// Take back the requests that timed out and ask the next peer that announced each item, or
+    /// give up on the item if there is none. Returns the peers to ask, with the hashes to ask for.
+    /// Only checks once per `TIMEOUT_CHECK_INTERVAL`, so it can be called for every message.
+    pub fn timed_out(&mut self) -> Vec<(peer::Handle, Vec<H256>)> {
+        self.timed_out_at(Instant::now())
+    }
+
+    fn timed_out_at(&mut self, now: Instant) -> Vec<(peer::Handle, Vec<H256>)> {
+        if now.saturating_duration_since(self.last_check) < TIMEOUT_CHECK_INTERVAL {
+            return vec![];
+        }
+        self.last_check = now;
+        let mut retries: HashMap<SocketAddr, (peer::Handle, Vec<H256>)> = HashMap::new();
+        self.in_flight.retain(|hash, request| {
+            if now.saturating_duration_since(request.asked) < REQUEST_TIMEOUT {
+                return true;
+            }
+            match request.announcers.pop_front() {
+                Some(next) => {
+                    request.peer = *next.addr();
+                    request.asked = now;
+                    retries.entry(*next.addr()).or_insert_with(|| (next, vec![])).1.push(*hash);
+                    true
+                }
+                None => false,
+            }
+        });
+        retries.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::generate_random_hash;
+
+    #[test]
+    fn forget_oldest_known() {
+        let mut known = KnownInventory::new(2);
+        let hashes: Vec<H256> = (0..3).map(|_| generate_random_hash()).collect();
+        for hash in hashes.iter() {
+            known.insert(*hash);
+        }
+        assert!(!known.contains(&hashes[0]));
+        assert!(known.contains(&hashes[1]));
+        assert!(known.contains(&hashes[2]));
+    }
+
+    #[test]
+    fn retry_from_another_announcer() {
+        let (first, _first_receiver) = peer::Handle::test_handle_at("127.0.0.1:6001".parse().unwrap());
+        let (second, _second_receiver) = peer::Handle::test_handle_at("127.0.0.1:6002".parse().unwrap());
+        let hash = generate_random_hash();
+        let mut requests = Requests::new();
+        assert!(requests.announced(hash, &first));
+        assert!(!requests.announced(hash, &second));
+        assert!(!requests.announced(hash, &first));
+
+        let start = Instant::now();
+        assert!(requests.timed_out_at(start + REQUEST_TIMEOUT / 2).is_empty());
+        let retries = requests.timed_out_at(start + REQUEST_TIMEOUT * 2);
+        assert_eq!(retries.len(), 1);
+        assert_eq!(retries[0].0.addr(), second.addr());
+        assert_eq!(retries[0].1, vec![hash]);
+        // nobody else announced it
+        assert!(requests.timed_out_at(start + REQUEST_TIMEOUT * 4).is_empty());
+        assert!(requests.announced(hash, &first));
+        requests.received(&hash);
+        assert!(requests.in_flight.is_empty());
+    }
+}
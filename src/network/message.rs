@@ -1,6 +1,26 @@
 use serde::{Serialize, Deserialize};
+use std::net::SocketAddr;
 
-use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use super::compact::CompactBlock;
+
+use crate::types::{hash::H256, block::{Block, Header}, transaction::SignedTransaction};
+
+/// Version of the protocol spoken by this node.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version this node can talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// What a peer says about itself when connecting.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub version: u32,
+    pub genesis: H256,
+    pub tip: H256,
+    pub height: u64,
+    /// Address the peer accepts connections on, which for an incoming peer is not the address
+    /// it connected from
+    pub listen_addr: SocketAddr,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -12,4 +32,46 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    /// Block locator of the sender's longest chain
+    GetHeaders(Vec<H256>),
+    Headers(Vec<Header>),
+    /// First message on a connection, from each side
+    Version(Version),
+    /// Acknowledges the peer's `Version`, the connection is usable once both sides sent it
+    VerAck,
+    /// Asks for addresses of other peers
+    GetAddr,
+    /// Addresses of peers that accept connections
+    Addr(Vec<SocketAddr>),
+    /// A new block, to rebuild from the mempool
+    CompactBlock(CompactBlock),
+    /// Asks for the transactions of a block at the given positions
+    GetBlockTxn(H256, Vec<u32>),
+    /// Transactions of a block, in the order they were asked for
+    BlockTxn(H256, Vec<SignedTransaction>),
+}
+
+impl Message {
+    /// Name of the message type, the same for every message of a variant
+    pub fn command(&self) -> &'static str {
+        match self {
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::NewBlockHashes(_) => "new_block_hashes",
+            Message::GetBlocks(_) => "get_blocks",
+            Message::Blocks(_) => "blocks",
+            Message::NewTransactionHashes(_) => "new_transaction_hashes",
+            Message::GetTransactions(_) => "get_transactions",
+            Message::Transactions(_) => "transactions",
+            Message::GetHeaders(_) => "get_headers",
+            Message::Headers(_) => "headers",
+            Message::Version(_) => "version",
+            Message::VerAck => "verack",
+            Message::GetAddr => "get_addr",
+            Message::Addr(_) => "addr",
+            Message::CompactBlock(_) => "compact_block",
+            Message::GetBlockTxn(..) => "get_block_txn",
+            Message::BlockTxn(..) => "block_txn",
+        }
+    }
 }
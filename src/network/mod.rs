@@ -1,4 +1,11 @@
+pub mod addr_book;
+pub mod compact;
+pub mod inventory;
 pub mod message;
 pub mod peer;
+pub mod rate_limit;
 pub mod server;
+pub mod sync;
+pub mod transport;
+pub mod wire;
 pub mod worker;
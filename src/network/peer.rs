@@ -1,16 +1,33 @@
-use super::message::Message;
-use futures::{channel::mpsc, sink::SinkExt};
-use log::trace;
+use super::inventory::{KnownInventory, MAX_KNOWN_INVENTORY};
+use super::message::{Message, Version};
+use super::rate_limit::{RateLimiter, RateLimits};
+use super::transport::PeerId;
+use super::wire::{self, Magic};
+use crate::types::hash::{H256, Hashable};
+use log::{debug, trace};
+use smol::channel::{self, TrySendError};
 use smol::Async;
+use std::sync::{Arc, Mutex};
 
+/// Create the handle of a new peer on the network with `magic`, with the receiving end of its
+/// write queue. The queue holds at most `write_queue_len` messages, and messages from the peer
+/// are limited by `limits`.
 pub fn new(
     stream: &Async<std::net::TcpStream>,
-) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
-    let (write_sender, write_receiver) = mpsc::unbounded();
+    magic: Magic,
+    write_queue_len: usize,
+    limits: RateLimits,
+) -> std::io::Result<(channel::Receiver<Vec<u8>>, Handle)> {
+    let (write_sender, write_receiver) = channel::bounded(write_queue_len);
     let addr = stream.get_ref().peer_addr()?;
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        magic,
+        version: Arc::new(Mutex::new(None)),
+        identity: Arc::new(Mutex::new(None)),
+        limiter: Arc::new(Mutex::new(RateLimiter::new(limits))),
+        known: Arc::new(Mutex::new(KnownInventory::new(MAX_KNOWN_INVENTORY))),
     };
     Ok((write_receiver, handle))
 }
@@ -24,37 +41,130 @@ pub enum Direction {
 #[derive(Clone, Debug)]
 pub struct Handle {
     addr: std::net::SocketAddr,
-    write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    /// Magic of the network, which messages to and from the peer carry
+    magic: Magic,
+    write_queue: channel::Sender<Vec<u8>>,
+    /// What the peer announced in its handshake, set once the handshake is complete
+    version: Arc<Mutex<Option<Version>>>,
+    /// Identity key the peer proved, only on encrypted connections
+    identity: Arc<Mutex<Option<PeerId>>>,
+    limiter: Arc<Mutex<RateLimiter>>,
+    /// Blocks and transactions the peer has, which are not announced to it
+    known: Arc<Mutex<KnownInventory>>,
 }
 
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver {
-    r: mpsc::UnboundedReceiver<Vec<u8>>
+    r: channel::Receiver<Vec<u8>>,
+    magic: Magic,
 }
 
 impl Handle {
+    /// Queue a message for the peer. A peer that does not keep up with its queue is
+    /// disconnected, rather than holding up the sender.
     pub fn write(&mut self, msg: Message) {
-        let buffer = bincode::serialize(&msg).unwrap();
-        smol::block_on(async move {
-            if self.write_queue.send(buffer).await.is_err() {
-                trace!("Trying to send to disconnected peer");
+        let buffer = wire::encode(self.magic, &msg);
+        match self.write_queue.try_send(buffer) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                debug!("Write queue of peer {} full, disconnecting", self.addr);
+                self.close();
             }
-        });
+            Err(TrySendError::Closed(_)) => trace!("Trying to send to disconnected peer"),
+        }
+    }
+
+    /// Record that the peer has a block or transaction
+    pub fn mark_known(&self, hash: H256) {
+        self.known.lock().unwrap().insert(hash);
+    }
+
+    /// Get the part of an announcement the peer does not know about yet, recording it as known,
+    /// or `None` if there is nothing left to announce. Other messages are returned as they are.
+    pub fn unknown_part(&self, msg: &Message) -> Option<Message> {
+        let mut known = self.known.lock().unwrap();
+        let mut take_unknown = |hashes: &[H256]| -> Vec<H256> {
+            let unknown: Vec<H256> = hashes.iter().filter(|h| !known.contains(h)).copied().collect();
+            for hash in unknown.iter() {
+                known.insert(*hash);
+            }
+            unknown
+        };
+        let (unknown, msg) = match msg {
+            Message::NewBlockHashes(hashes) => {
+                let unknown = take_unknown(hashes);
+                (unknown.len(), Message::NewBlockHashes(unknown))
+            }
+            Message::NewTransactionHashes(hashes) => {
+                let unknown = take_unknown(hashes);
+                (unknown.len(), Message::NewTransactionHashes(unknown))
+            }
+            Message::CompactBlock(compact) => (take_unknown(&[compact.hash()]).len(), msg.clone()),
+            _ => return Some(msg.clone()),
+        };
+        if unknown == 0 {
+            return None;
+        }
+        Some(msg)
+    }
+
+    /// Whether the peer is within its rate limit for this type of message, counting it if so
+    pub fn allow(&self, msg: &Message) -> bool {
+        self.limiter.lock().unwrap().allow(msg)
+    }
+
+    pub fn magic(&self) -> Magic {
+        self.magic
     }
 
     pub fn addr(&self) -> &std::net::SocketAddr {
         &self.addr
     }
 
+    /// Get what the peer announced in its handshake, `None` until the handshake is complete
+    pub fn version(&self) -> Option<Version> {
+        self.version.lock().unwrap().clone()
+    }
+
+    pub(super) fn set_version(&self, version: Version) {
+        *self.version.lock().unwrap() = Some(version);
+    }
+
+    /// Get the identity the peer proved, `None` unless the connection is encrypted
+    pub fn identity(&self) -> Option<PeerId> {
+        *self.identity.lock().unwrap()
+    }
+
+    pub(super) fn set_identity(&self, identity: PeerId) {
+        *self.identity.lock().unwrap() = Some(identity);
+    }
+
+    /// Stop writing to the peer, which ends its connection
+    pub(super) fn close(&self) {
+        self.write_queue.close();
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
-        let (s,r) = mpsc::unbounded();
+        Self::test_handle_at(std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321))
+    }
+
+    #[cfg(any(test,test_utilities))]
+    pub fn test_handle_at(addr: std::net::SocketAddr) -> (Handle, TestReceiver) {
+        let (s,r) = channel::unbounded();
+        let magic = *b"test";
         (Handle {
-            addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
+            addr,
+            magic,
             write_queue: s,
+            version: Arc::new(Mutex::new(None)),
+            identity: Arc::new(Mutex::new(None)),
+            limiter: Arc::new(Mutex::new(RateLimiter::new(RateLimits::default()))),
+            known: Arc::new(Mutex::new(KnownInventory::new(MAX_KNOWN_INVENTORY))),
         },
         TestReceiver {
-            r
+            r,
+            magic,
         })
     }
 }
@@ -62,8 +172,8 @@ impl Handle {
 #[cfg(any(test,test_utilities))]
 impl TestReceiver {
     pub fn recv(&mut self) -> Message {
-        let bytes = smol::block_on(futures::stream::StreamExt::next(&mut self.r)).unwrap();
-        let msg: Message = bincode::deserialize(&bytes).unwrap();
+        let bytes = smol::block_on(self.r.recv()).unwrap();
+        let msg: Message = wire::decode(self.magic, &bytes).unwrap();
         msg
     }
 }
\ No newline at end of file
@@ -0,0 +1,112 @@
+use super::message::Message;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// How many messages of one type a peer may send: `burst` at once, refilled at `per_second`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+/// Inbound message limits by message type, see `Message::command`. Types without a limit are
+/// not limited.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub limits: HashMap<&'static str, Limit>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        let limit = |per_second, burst| Limit { per_second, burst };
+        let limits = vec![
+            ("version", limit(0.1, 2.0)),
+            ("ping", limit(1.0, 5.0)),
+            ("pong", limit(1.0, 5.0)),
+            ("new_block_hashes", limit(50.0, 200.0)),
+            ("get_blocks", limit(20.0, 100.0)),
+            ("blocks", limit(20.0, 100.0)),
+            ("new_transaction_hashes", limit(200.0, 1000.0)),
+            ("get_transactions", limit(200.0, 1000.0)),
+            ("transactions", limit(200.0, 1000.0)),
+            ("get_headers", limit(5.0, 20.0)),
+            ("headers", limit(5.0, 20.0)),
+            ("get_addr", limit(0.1, 2.0)),
+            ("addr", limit(1.0, 10.0)),
+            ("compact_block", limit(20.0, 100.0)),
+            ("get_block_txn", limit(20.0, 100.0)),
+            ("block_txn", limit(20.0, 100.0)),
+        ];
+        RateLimits {
+            limits: limits.into_iter().collect(),
+        }
+    }
+}
+
+/// Token buckets of one peer, one per limited message type.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    /// Tokens left and when they were last refilled
+    buckets: HashMap<&'static str, (f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            limits,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take a token for `msg`, returns false if the peer is over its limit for this type
+    pub fn allow(&mut self, msg: &Message) -> bool {
+        self.allow_at(msg.command(), Instant::now())
+    }
+
+    fn allow_at(&mut self, command: &'static str, now: Instant) -> bool {
+        let limit = match self.limits.limits.get(command) {
+            Some(limit) => *limit,
+            None => return true,
+        };
+        let (tokens, last) = self.buckets.entry(command).or_insert((limit.burst, now));
+        let elapsed = now.saturating_duration_since(*last).as_secs_f64();
+        *tokens = (*tokens + elapsed * limit.per_second).min(limit.burst);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn refill_over_time() {
+        let mut limits = RateLimits::default();
+        limits.limits.insert("ping", Limit { per_second: 2.0, burst: 3.0 });
+        let mut limiter = RateLimiter::new(limits);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.allow_at("ping", start));
+        }
+        assert!(!limiter.allow_at("ping", start));
+        // other types have their own bucket
+        assert!(limiter.allow_at("pong", start));
+        assert!(limiter.allow_at("verack", start));
+        // half a second refills one token, and the bucket never holds more than the burst
+        assert!(limiter.allow_at("ping", start + Duration::from_millis(500)));
+        assert!(!limiter.allow_at("ping", start + Duration::from_millis(500)));
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.allow_at("ping", later));
+        }
+        assert!(!limiter.allow_at("ping", later));
+    }
+}
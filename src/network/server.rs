@@ -1,32 +1,98 @@
-use crate::types::address::Address;
+use crate::blockchain::{Blockchain, TipInfo};
+use crate::types::hash::H256;
+use super::addr_book::AddressBook;
+use super::compact::RelayStats;
 use super::peer;
-use super::message;
+use super::rate_limit::RateLimits;
+use super::transport::{Encryption, Handshake, Identity, PeerId, SEAL_OVERHEAD};
+use super::wire::{self, Magic, WireError};
+use super::message::{self, Message, Version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 
 use async_dup::Arc as AsyncArc;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use futures::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
 use futures::{channel::oneshot, stream::StreamExt};
 use smol::{Async, Executor};
-use log::{debug, info, trace};
+use log::{debug, error, info, trace};
+use std::collections::{HashMap, HashSet};
 use std::net;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+/// How often the number of outbound connections is checked.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to wait for an outbound connection to open.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Misbehavior points for announcing a message over the size limit.
+const OVERSIZED_FRAME_PENALTY: u32 = 20;
+
+/// Settings of the connection manager.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Number of outbound connections to keep open
+    pub outbound_peers: usize,
+    /// Misbehavior score at which a peer is banned
+    pub ban_threshold: u32,
+    pub ban_duration: Duration,
+    /// Largest message accepted from a peer, in bytes
+    pub max_message_bytes: usize,
+    /// Messages queued for a peer before it is considered too slow and disconnected
+    pub write_queue_len: usize,
+    pub rate_limits: RateLimits,
+    /// Whether connections with peers are encrypted
+    pub encryption: Encryption,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            outbound_peers: 8,
+            ban_threshold: 100,
+            ban_duration: Duration::from_secs(24 * 60 * 60),
+            max_message_bytes: 16 * 1024 * 1024,
+            write_queue_len: 1000,
+            rate_limits: RateLimits::default(),
+            encryption: Encryption::Preferred,
+        }
+    }
+}
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    addr_book: &Arc<Mutex<AddressBook>>,
+    identity: Identity,
+    config: Config,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
+    let blockchain = blockchain.lock().unwrap();
+    let dropped_subscribers = Arc::new(Mutex::new(vec![]));
     let handle = Handle {
         control_chan: control_signal_sender.clone(),
+        addr_book: Arc::clone(addr_book),
+        relay_stats: Arc::new(Mutex::new(RelayStats::default())),
+        dropped_subscribers: Arc::clone(&dropped_subscribers),
     };
     let ctx = Context {
         peers: std::collections::HashMap::new(),
         addr,
+        magic: wire::network_magic(&blockchain.genesis()),
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        genesis: blockchain.genesis(),
+        tip: blockchain.tip_info(),
+        addr_book: Arc::clone(addr_book),
+        outbound: HashSet::new(),
+        connecting: HashSet::new(),
+        banned: HashMap::new(),
+        scores: HashMap::new(),
+        identity: Arc::new(identity),
+        dropped_subscribers,
+        config,
     };
     Ok((ctx, handle))
 }
@@ -34,9 +100,64 @@ pub fn new(
 pub struct Context {
     peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
     addr: std::net::SocketAddr,
+    /// Magic of the network, derived from the genesis block
+    magic: Magic,
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    genesis: H256,
+    /// The tip, kept up to date by the blockchain, so the async tasks never wait on its lock
+    tip: Arc<Mutex<TipInfo>>,
+    addr_book: Arc<Mutex<AddressBook>>,
+    /// Peers we connected to, as opposed to peers that connected to us
+    outbound: HashSet<net::SocketAddr>,
+    /// Addresses with a connection attempt underway
+    connecting: HashSet<net::SocketAddr>,
+    /// Hosts refused until the given time, in either direction
+    banned: HashMap<net::IpAddr, Instant>,
+    /// Misbehavior points of the connected peers that have any
+    scores: HashMap<net::SocketAddr, u32>,
+    /// Key this node proves its identity with on encrypted connections
+    identity: Arc<Identity>,
+    /// Notified of every peer that disconnected or was dropped
+    dropped_subscribers: Arc<Mutex<Vec<Sender<net::SocketAddr>>>>,
+    config: Config,
+}
+
+/// A connected peer, as listed by `Handle::peers`.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: net::SocketAddr,
+    /// Whether we connected to the peer, rather than the peer to us
+    pub outbound: bool,
+    /// What the peer announced in its handshake, `None` until the handshake is complete
+    pub version: Option<Version>,
+    /// Identity the peer proved, `None` unless the connection is encrypted
+    pub identity: Option<PeerId>,
+    /// Misbehavior points, the peer is banned when they reach the threshold
+    pub score: u32,
+}
+
+/// Describe this node for the handshake.
+fn local_version(genesis: H256, tip: TipInfo, listen_addr: net::SocketAddr) -> Version {
+    Version {
+        version: PROTOCOL_VERSION,
+        genesis,
+        tip: tip.hash,
+        height: tip.height,
+        listen_addr,
+    }
+}
+
+/// Check that we can talk to a peer that sent `version`.
+fn check_version(version: &Version, genesis: &H256) -> Result<(), String> {
+    if version.version < MIN_PROTOCOL_VERSION {
+        return Err(format!("protocol version {} is too old", version.version));
+    }
+    if version.genesis != *genesis {
+        return Err(format!("different genesis {}", version.genesis));
+    }
+    Ok(())
 }
 
 impl Context {
@@ -46,6 +167,7 @@ impl Context {
         let listener = Async::<net::TcpListener>::bind(self.addr)?;
         info!("P2P server listening at {}", self.addr);
         let control_chan = self.control_sender.clone();
+        let maintenance_chan = self.control_sender.clone();
         let ex = Executor::new();
         let ex = Arc::new(ex);
         let ex_clone = ex.clone();
@@ -57,6 +179,13 @@ impl Context {
             Self::listener_loop(listener, control_chan).await.unwrap();
         })
             .detach();
+        ex.spawn(async move {
+            // replace dropped outbound peers, starting right away
+            while maintenance_chan.send(ControlSignal::MaintainConnections).await.is_ok() {
+                smol::Timer::after(MAINTENANCE_INTERVAL).await;
+            }
+        })
+            .detach();
         thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
         return Ok(());
     }
@@ -83,31 +212,198 @@ impl Context {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
                     let handle = self.connect(&addr, ex.clone()).await;
+                    if handle.is_ok() {
+                        self.outbound.insert(addr);
+                    }
                     result_chan.send(handle).unwrap();
                 }
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
+                    // peers still in the handshake only get messages once it is complete, and
+                    // peers are not told about what they already have
                     for (_, hd) in self.peers.iter_mut() {
-                        hd.write(msg.clone());
+                        if hd.version().is_none() {
+                            continue;
+                        }
+                        if let Some(msg) = hd.unknown_part(&msg) {
+                            hd.write(msg);
+                        }
                     }
                 }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
-                    self.accept(stream, ex.clone()).await?;
+                    // the socket may have been reset before it got here
+                    let ip = match stream.get_ref().peer_addr() {
+                        Ok(addr) => addr.ip(),
+                        Err(e) => {
+                            debug!("Dropped incoming connection: {}", e);
+                            continue;
+                        }
+                    };
+                    if self.is_banned(&ip) {
+                        debug!("Refused banned peer {}", ip);
+                        continue;
+                    }
+                    if let Err(e) = self.accept(stream, ex.clone()).await {
+                        debug!("Error accepting peer {}: {}", ip, e);
+                    }
+                }
+                ControlSignal::MaintainConnections => {
+                    trace!("Processing MaintainConnections command");
+                    self.maintain_connections(&ex);
+                }
+                ControlSignal::OutboundConnected(addr, result) => {
+                    trace!("Processing OutboundConnected({})", addr);
+                    self.connecting.remove(&addr);
+                    let registered = match result {
+                        Ok(_) if self.is_banned(&addr.ip()) => {
+                            debug!("Dropped connection to {}, banned while connecting", addr);
+                            continue;
+                        }
+                        Ok(stream) => self.register(stream, peer::Direction::Outgoing, ex.clone()).await,
+                        Err(e) => Err(e),
+                    };
+                    match registered {
+                        Ok(_) => {
+                            info!("Connected to outgoing peer {}", addr);
+                            self.outbound.insert(addr);
+                        }
+                        Err(e) => {
+                            debug!("Error connecting to peer {}: {}", addr, e);
+                            self.addr_book.lock().unwrap().mark_failed(addr);
+                        }
+                    }
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
-                    self.peers.remove(&addr);
-                    info!("Peer {} disconnected", addr);
+                    self.outbound.remove(&addr);
+                    self.scores.remove(&addr);
+                    if let Some(hd) = self.peers.remove(&addr) {
+                        hd.close();
+                        info!("Peer {} disconnected", addr);
+                    }
+                    // every connection ends here, whether the peer left, was disconnected or
+                    // was banned
+                    self.dropped_subscribers.lock().unwrap().retain(|s| s.send(addr).is_ok());
+                }
+                ControlSignal::SendToPeer(addr, msg) => {
+                    trace!("Processing SendToPeer({})", addr);
+                    match self.peers.get_mut(&addr) {
+                        Some(hd) => hd.write(msg),
+                        None => debug!("Dropped message to unknown peer {}", addr),
+                    }
+                }
+                ControlSignal::GetPeers(result_chan) => {
+                    trace!("Processing GetPeers command");
+                    let peers = self
+                        .peers
+                        .values()
+                        .map(|hd| PeerInfo {
+                            addr: *hd.addr(),
+                            outbound: self.outbound.contains(hd.addr()),
+                            version: hd.version(),
+                            identity: hd.identity(),
+                            score: self.scores.get(hd.addr()).copied().unwrap_or(0),
+                        })
+                        .collect();
+                    // the caller may have given up waiting
+                    let _ = result_chan.send(peers);
+                }
+                ControlSignal::Disconnect(addr) => {
+                    trace!("Processing Disconnect({})", addr);
+                    self.disconnect(&addr);
+                }
+                ControlSignal::Ban(ip, duration) => {
+                    trace!("Processing Ban({})", ip);
+                    self.ban(ip, duration);
                 }
-                ControlSignal::SendToPeer((_receiver, _msg)) => {
-                    unimplemented!()
+                ControlSignal::Misbehaving(addr, points, reason) => {
+                    trace!("Processing Misbehaving({})", addr);
+                    self.misbehaving(addr, points, &reason);
                 }
             }
         }
         return Ok(());
     }
 
+    /// Close the connection to a peer. Its tasks notice and report it dropped.
+    fn disconnect(&mut self, addr: &net::SocketAddr) {
+        self.outbound.remove(addr);
+        self.scores.remove(addr);
+        if let Some(hd) = self.peers.remove(addr) {
+            hd.close();
+            info!("Disconnected peer {}", addr);
+        }
+    }
+
+    /// Disconnect all the peers at `ip` and refuse connections with it for `duration`
+    fn ban(&mut self, ip: net::IpAddr, duration: Duration) {
+        info!("Banning {} for {:?}", ip, duration);
+        self.banned.insert(ip, Instant::now() + duration);
+        let addrs: Vec<net::SocketAddr> = self.peers.keys().filter(|a| a.ip() == ip).copied().collect();
+        for addr in addrs {
+            self.disconnect(&addr);
+        }
+    }
+
+    /// Add misbehavior points to a connected peer, and ban it once it reaches the threshold
+    fn misbehaving(&mut self, addr: net::SocketAddr, points: u32, reason: &str) {
+        if !self.peers.contains_key(&addr) {
+            return;
+        }
+        let score = self.scores.entry(addr).or_insert(0);
+        *score = score.saturating_add(points);
+        info!("Peer {} misbehaved ({}), score {}", addr, reason, score);
+        if *score >= self.config.ban_threshold {
+            self.ban(addr.ip(), self.config.ban_duration);
+        }
+    }
+
+    /// Whether `ip` is banned, forgetting the bans that expired
+    fn is_banned(&mut self, ip: &net::IpAddr) -> bool {
+        let now = Instant::now();
+        self.banned.retain(|_, until| *until > now);
+        self.banned.contains_key(ip)
+    }
+
+    /// Start connecting to addresses from the address book until the outbound connections,
+    /// established or underway, reach the target. Also saves the address book.
+    fn maintain_connections(&mut self, ex: &Arc<Executor<'_>>) {
+        let addr_book = Arc::clone(&self.addr_book);
+        let mut addr_book = addr_book.lock().unwrap();
+        if let Err(e) = addr_book.save() {
+            error!("Error saving address book: {}", e);
+        }
+        // skip the peers we are connected to either way, and ourselves
+        let mut exclude: HashSet<net::SocketAddr> = self.peers.keys().copied().collect();
+        exclude.extend(self.peers.values().filter_map(|hd| hd.version()).map(|v| v.listen_addr));
+        exclude.extend(self.connecting.iter().copied());
+        exclude.insert(self.addr);
+        while self.outbound.len() + self.connecting.len() < self.config.outbound_peers {
+            let addr = match addr_book.pick(&exclude) {
+                Some(addr) => addr,
+                None => break,
+            };
+            exclude.insert(addr);
+            if self.is_banned(&addr.ip()) {
+                continue;
+            }
+            self.connecting.insert(addr);
+            debug!("Establishing connection to peer {}", addr);
+            let control_chan = self.control_sender.clone();
+            ex.spawn(async move {
+                let timeout = async {
+                    smol::Timer::after(CONNECT_TIMEOUT).await;
+                    Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "connection timed out"))
+                };
+                let result = smol::future::or(Async::<net::TcpStream>::connect(addr), timeout).await;
+                // the server only stops with the process
+                let _ = control_chan.send(ControlSignal::OutboundConnected(addr, result)).await;
+            })
+                .detach();
+        }
+    }
+
     /// Connect to a peer, and register this peer
     async fn connect(
         &mut self,
@@ -133,16 +429,36 @@ impl Context {
     async fn register(
         &mut self,
         stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        direction: peer::Direction,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+        let (mut write_queue, mut handle) =
+            peer::new(&stream, self.magic, self.config.write_queue_len, self.config.rate_limits.clone())?;
 
         let stream = AsyncArc::new(stream);
         let new_msg_chan = self.new_msg_chan.clone();
-        let handle_copy = handle.clone();
+        let mut handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
         let addr = stream.get_ref().peer_addr()?;
+        let genesis = self.genesis;
+        let addr_book = Arc::clone(&self.addr_book);
+        let reader_control_chan = self.control_sender.clone();
+        let max_message_bytes = self.config.max_message_bytes;
+        let identity = Arc::clone(&self.identity);
+        let magic = self.magic;
+
+        // the first frame each way is a hello to agree on the transport, the reader hands the
+        // writer what it needs to seal the rest once the peer's hello arrives
+        let initiator = matches!(direction, peer::Direction::Outgoing);
+        let (handshake, hello) = Handshake::new(self.config.encryption, initiator);
+        let mut handshake = Some(handshake);
+        let (transport_sender, transport_receiver) = oneshot::channel();
+        let mut transport_sender = Some(transport_sender);
+
+        // both sides open with their version, and only exchange other messages once each
+        // acknowledged the other's
+        let version = local_version(self.genesis, *self.tip.lock().unwrap(), self.addr);
+        handle.write(Message::Version(version));
 
         // start the reactor for this peer
         // first, start a task that keeps reading from this guy
@@ -152,6 +468,12 @@ impl Context {
             let mut size_buffer: [u8; 4] = [0; 4];
             // the buffer to store the message content
             let mut msg_buffer: Vec<u8> = vec![];
+            let mut peer_version: Option<Version> = None;
+            let mut acked = false;
+            let mut established = false;
+            // set when encrypting, until the peer proved its identity
+            let mut opener = None;
+            let mut authenticated = false;
             loop {
                 // first, read exactly 4 bytes to get the frame header
                 let msg_size = match reader.read_exact(&mut size_buffer).await {
@@ -160,6 +482,14 @@ impl Context {
                         break;
                     }
                 };
+                // refuse to allocate for a message over the limit
+                if msg_size as usize > max_message_bytes + SEAL_OVERHEAD {
+                    info!("Peer {} sent a message of {} bytes, over the limit", addr, msg_size);
+                    let _ = reader_control_chan
+                        .send(ControlSignal::Misbehaving(addr, OVERSIZED_FRAME_PENALTY, "oversized message".to_string()))
+                        .await;
+                    break;
+                }
                 // then, read exactly msg_size bytes to get the whole message
                 if msg_buffer.len() < msg_size as usize {
                     msg_buffer.resize(msg_size as usize, 0);
@@ -169,51 +499,149 @@ impl Context {
                     .await
                 {
                     Ok(_) => {
-                        let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
-                        new_msg_chan
-                            .send((new_payload, handle_copy.clone()))
-                            .await
-                            .unwrap();
+                        let mut new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
+                        if let Some(handshake) = handshake.take() {
+                            let session = match handshake.agree(&new_payload, &identity) {
+                                Ok(session) => session,
+                                Err(e) => {
+                                    info!("Rejected peer {}: {}", addr, e);
+                                    if let peer::Direction::Outgoing = direction {
+                                        addr_book.lock().unwrap().mark_failed(addr);
+                                    }
+                                    break;
+                                }
+                            };
+                            let sealer = session.map(|session| {
+                                opener = Some(session.opener);
+                                (session.sealer, session.auth)
+                            });
+                            debug!("Talking to {} {}", addr, if sealer.is_some() { "encrypted" } else { "in the clear" });
+                            let _ = transport_sender.take().unwrap().send(sealer);
+                            continue;
+                        }
+                        if let Some(opener) = opener.as_mut() {
+                            new_payload = match opener.open(new_payload) {
+                                Ok(payload) => payload,
+                                Err(e) => {
+                                    info!("Dropping peer {}: {}", addr, e);
+                                    break;
+                                }
+                            };
+                            if !authenticated {
+                                match opener.authenticate(&new_payload) {
+                                    Ok(id) if id == identity.id() => {
+                                        debug!("Connected to ourselves at {}", addr);
+                                        break;
+                                    }
+                                    Ok(id) => {
+                                        debug!("Peer {} proved identity {}", addr, id);
+                                        handle_copy.set_identity(id);
+                                        authenticated = true;
+                                        continue;
+                                    }
+                                    Err(e) => {
+                                        info!("Rejected peer {}: {}", addr, e);
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+                        if established {
+                            new_msg_chan
+                                .send((new_payload, handle_copy.clone()))
+                                .await
+                                .unwrap();
+                            continue;
+                        }
+                        match wire::decode(magic, &new_payload) {
+                            Ok(Message::Version(version)) => {
+                                if let Err(e) = check_version(&version, &genesis) {
+                                    info!("Rejected peer {}: {}", addr, e);
+                                    if let peer::Direction::Outgoing = direction {
+                                        addr_book.lock().unwrap().mark_failed(addr);
+                                    }
+                                    break;
+                                }
+                                handle_copy.write(Message::VerAck);
+                                peer_version = Some(version);
+                            }
+                            Ok(Message::VerAck) => {
+                                acked = true;
+                            }
+                            Err(e @ WireError::WrongMagic(_)) => {
+                                info!("Rejected peer {}: {}", addr, e);
+                                if let peer::Direction::Outgoing = direction {
+                                    addr_book.lock().unwrap().mark_failed(addr);
+                                }
+                                break;
+                            }
+                            _ => {
+                                trace!("Ignoring message from {} before the handshake", addr);
+                            }
+                        }
+                        if let (Some(version), true) = (&peer_version, acked) {
+                            established = true;
+                            handle_copy.set_version(version.clone());
+                            info!("Handshake with {} complete, at height {}", addr, version.height);
+                            // an unspecified listening address means the one the peer connects from
+                            let mut listen_addr = version.listen_addr;
+                            if listen_addr.ip().is_unspecified() {
+                                listen_addr.set_ip(addr.ip());
+                            }
+                            match direction {
+                                peer::Direction::Outgoing => {
+                                    addr_book.lock().unwrap().mark_good(addr);
+                                    // learn about more peers from the ones we chose
+                                    handle_copy.write(Message::GetAddr);
+                                }
+                                peer::Direction::Incoming => addr_book.lock().unwrap().add(listen_addr),
+                            }
+                            // the workers catch up with the peer if it is ahead, they have the
+                            // blockchain at hand
+                            let msg = wire::encode(magic, &Message::Version(version.clone()));
+                            new_msg_chan.send((msg, handle_copy.clone())).await.unwrap();
+                        }
                     }
                     Err(_) => {
                         break;
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, or was rejected, stop writing to it too
+            handle_copy.close();
         })
             .detach();
 
         // second, start a task that keeps writing to this guy
         let mut writer = BufWriter::new(stream.clone());
         ex.spawn(async move {
-            loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
-
-                // second, encode the length of the message
-                let size_buffer = (new_msg.len() as u32).to_be_bytes();
-
-                // third, write the frame header and the payload
-                match writer.write_all(&size_buffer).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
-                }
-                match writer.write_all(&new_msg).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
-                }
-                match writer.flush().await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
+            // the hello goes out in the clear, then the identity proof and the messages are
+            // sealed if both sides agreed to encrypt
+            let mut sealer = None;
+            let mut open = write_frame(&mut writer, &hello).await.is_ok();
+            if open {
+                match transport_receiver.await {
+                    Ok(Some((session_sealer, auth))) => {
+                        open = write_frame(&mut writer, &auth).await.is_ok();
+                        sealer = Some(session_sealer);
                     }
+                    Ok(None) => {}
+                    // the reader gave up on the peer
+                    Err(_) => open = false,
                 }
             }
+            // then, get a message to write from the queue, until the queue is closed
+            while open {
+                let new_msg = match write_queue.next().await {
+                    Some(new_msg) => new_msg,
+                    None => break,
+                };
+                let frame = match sealer.as_mut() {
+                    Some(sealer) => sealer.seal(&new_msg),
+                    None => new_msg,
+                };
+                open = write_frame(&mut writer, &frame).await.is_ok();
+            }
             // the peer is disconnected
             control_chan
                 .send(ControlSignal::DroppedPeer(addr))
@@ -228,9 +656,19 @@ impl Context {
     }
 }
 
+/// Write a frame: the length of the payload, then the payload.
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await
+}
+
 #[derive(Clone)]
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
+    addr_book: Arc<Mutex<AddressBook>>,
+    relay_stats: Arc<Mutex<RelayStats>>,
+    dropped_subscribers: Arc<Mutex<Vec<Sender<net::SocketAddr>>>>,
 }
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver{
@@ -263,14 +701,58 @@ impl Handle {
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
     }
 
-    pub fn send(&self, receiver: Address, msg: message::Message) {
-        smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
+    /// Send a message to one peer, it is dropped if the peer is not connected
+    pub fn send(&self, receiver: net::SocketAddr, msg: message::Message) {
+        smol::block_on(self.control_chan.send(ControlSignal::SendToPeer(receiver, msg))).unwrap();
+    }
+
+    /// List the connected peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetPeers(sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    pub fn disconnect(&self, addr: net::SocketAddr) {
+        smol::block_on(self.control_chan.send(ControlSignal::Disconnect(addr))).unwrap();
+    }
+
+    /// Add misbehavior points to a peer, which is banned once it has too many
+    pub fn misbehaving(&self, addr: net::SocketAddr, points: u32, reason: &str) {
+        smol::block_on(self.control_chan.send(ControlSignal::Misbehaving(addr, points, reason.to_string()))).unwrap();
+    }
+
+    /// Disconnect the peers at `ip` and refuse connections with it for `duration`
+    pub fn ban(&self, ip: net::IpAddr, duration: Duration) {
+        smol::block_on(self.control_chan.send(ControlSignal::Ban(ip, duration))).unwrap();
+    }
+
+    /// Get the addresses of known peers, shared with the connection manager
+    pub fn address_book(&self) -> &Arc<Mutex<AddressBook>> {
+        &self.addr_book
+    }
+
+    /// Get the traffic counters of block relay, updated by the network workers
+    pub fn relay_stats(&self) -> &Arc<Mutex<RelayStats>> {
+        &self.relay_stats
+    }
+
+    /// Get notified of the address of every peer that disconnected, was disconnected or banned
+    pub fn subscribe_dropped(&self) -> Receiver<net::SocketAddr> {
+        let (sender, receiver) = unbounded();
+        self.dropped_subscribers.lock().unwrap().push(sender);
+        receiver
     }
 
     #[cfg(any(test,test_utilities))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
-        let h = Handle {control_chan: s};
+        let h = Handle {
+            control_chan: s,
+            addr_book: Arc::new(Mutex::new(AddressBook::new())),
+            relay_stats: Arc::new(Mutex::new(RelayStats::default())),
+            dropped_subscribers: Arc::new(Mutex::new(vec![])),
+        };
         let t = TestReceiver {control_chan: r};
         (h,t)
     }
@@ -284,5 +766,72 @@ enum ControlSignal {
     BroadcastMessage(message::Message),
     GetNewPeer(Async<net::TcpStream>),
     DroppedPeer(std::net::SocketAddr),
-    SendToPeer((Address,message::Message)),
+    /// Open more outbound connections if there are fewer than the target
+    MaintainConnections,
+    /// An outbound connection attempt started by the connection manager finished
+    OutboundConnected(std::net::SocketAddr, std::io::Result<Async<net::TcpStream>>),
+    SendToPeer(std::net::SocketAddr, message::Message),
+    GetPeers(oneshot::Sender<Vec<PeerInfo>>),
+    Disconnect(std::net::SocketAddr),
+    Ban(std::net::IpAddr, Duration),
+    /// Misbehavior points for a peer, and why
+    Misbehaving(std::net::SocketAddr, u32, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::generate_random_hash;
+
+    #[test]
+    fn reject_incompatible_peers() {
+        let blockchain = Blockchain::new();
+        let listen_addr: net::SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let version = local_version(blockchain.genesis(), *blockchain.tip_info().lock().unwrap(), listen_addr);
+        assert_eq!(version.height, 0);
+        assert!(check_version(&version, &blockchain.genesis()).is_ok());
+
+        let mut other_chain = version.clone();
+        other_chain.genesis = generate_random_hash();
+        assert!(check_version(&other_chain, &blockchain.genesis()).is_err());
+        let mut old = version;
+        old.version = MIN_PROTOCOL_VERSION - 1;
+        assert!(check_version(&old, &blockchain.genesis()).is_err());
+    }
+
+    #[test]
+    fn bans_expire() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let addr_book = Arc::new(Mutex::new(AddressBook::new()));
+        let (msg_sink, _msg_source) = smol::channel::unbounded();
+        let listen_addr: net::SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let (mut ctx, _handle) = new(listen_addr, msg_sink, &blockchain, &addr_book, Identity::generate(), Config::default()).unwrap();
+        let ip: net::IpAddr = "10.0.0.1".parse().unwrap();
+        ctx.ban(ip, Duration::from_secs(60));
+        ctx.ban("10.0.0.2".parse().unwrap(), Duration::from_millis(0));
+        assert!(ctx.is_banned(&ip));
+        assert!(!ctx.is_banned(&"10.0.0.2".parse().unwrap()));
+        assert!(!ctx.is_banned(&"10.0.0.3".parse().unwrap()));
+        assert_eq!(ctx.banned.len(), 1);
+    }
+
+    #[test]
+    fn ban_misbehaving_peer() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let addr_book = Arc::new(Mutex::new(AddressBook::new()));
+        let (msg_sink, _msg_source) = smol::channel::unbounded();
+        let listen_addr: net::SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let (mut ctx, _handle) = new(listen_addr, msg_sink, &blockchain, &addr_book, Identity::generate(), Config::default()).unwrap();
+        let (peer, _peer_receiver) = peer::Handle::test_handle();
+        let addr = *peer.addr();
+        ctx.peers.insert(addr, peer);
+
+        ctx.misbehaving(addr, 60, "test");
+        assert_eq!(ctx.scores[&addr], 60);
+        assert!(!ctx.is_banned(&addr.ip()));
+        ctx.misbehaving(addr, 60, "test");
+        assert!(ctx.is_banned(&addr.ip()));
+        assert!(ctx.peers.is_empty());
+        assert!(ctx.scores.is_empty());
+    }
 }
@@ -0,0 +1,417 @@
+use super::peer;
+use crate::blockchain::difficulty::block_work;
+use crate::blockchain::Blockchain;
+use crate::types::block::Header;
+use crate::types::hash::{H256, Hashable};
+use crate::types::u256::U256;
+use crate::validation::BlockError;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Most headers sent in one `Headers` message.
+pub const MAX_HEADERS: usize = 2000;
+/// Most blocks requested from one peer at a time.
+const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+/// How long a peer has to deliver a requested block before it is asked from another peer.
+const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most headers waiting for their block at a time.
+const MAX_PENDING_HEADERS: usize = 20_000;
+
+/// A header whose block is not in the blockchain yet.
+struct PendingHeader {
+    header: Header,
+    height: u64,
+    /// Total work of the chain ending at this header
+    work: U256,
+    /// Most total work of the known chains through this header, its block is only downloaded
+    /// if that is more than the tip has
+    best_work: U256,
+}
+
+/// Headers-first synchronization: headers announced by peers are checked for proof of work and
+/// linked into a header chain, then the blocks are downloaded in chain order from all the peers
+/// that sent headers, several at a time.
+///
+/// Only chains with more work than the tip are downloaded. A block that does not arrive in time is
+/// asked from another peer, and its branch is dropped once no peer is left to ask, so headers
+/// nobody sends the blocks of do not pile up.
+#[derive(Default)]
+pub struct HeaderSync {
+    /// Headers whose block is not in the blockchain yet
+    headers: HashMap<H256, PendingHeader>,
+    /// Blocks to download, parents first. Blocks already received are skipped lazily.
+    queue: VecDeque<H256>,
+    /// Requested blocks, with the peer asked and when
+    in_flight: HashMap<H256, (SocketAddr, Instant)>,
+    /// Peers to download blocks from
+    peers: HashMap<SocketAddr, peer::Handle>,
+}
+
+impl HeaderSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Download blocks from `peer` from now on
+    pub fn add_peer(&mut self, peer: &peer::Handle) {
+        self.peers.entry(*peer.addr()).or_insert_with(|| peer.clone());
+    }
+
+    /// Stop downloading blocks from a peer that is gone, the blocks it was asked for go back to
+    /// the queue for the other peers
+    pub fn remove_peer(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+        self.in_flight.retain(|_, (asked, _)| asked != addr);
+    }
+
+    /// Whether the block is announced by a header and waiting to be downloaded
+    pub fn is_pending(&self, hash: &H256) -> bool {
+        self.headers.contains_key(hash)
+    }
+
+    /// Check headers, in chain order, for proof of work, for the difficulty the retargeting rule
+    /// gives, and that they link to a known block or header, and keep them, as many as fit. Their
+    /// blocks are downloaded once they lead to a chain with more work than the tip, which may
+    /// take several messages on a long fork. Returns how many headers were new. One header
+    /// failing refuses the whole message, since the ones after it descend from it. Timestamps are
+    /// checked later, with the full block.
+    pub fn accept_headers(&mut self, headers: &[Header], blockchain: &Blockchain) -> Result<usize, BlockError> {
+        if self.headers.len() + headers.len() > MAX_PENDING_HEADERS {
+            self.trim(blockchain);
+        }
+        let mut checked: HashMap<H256, PendingHeader> = HashMap::new();
+        let mut order = vec![];
+        for header in headers {
+            let hash = header.hash();
+            if blockchain.has(hash) || self.headers.contains_key(&hash) || checked.contains_key(&hash) {
+                continue;
+            }
+            if self.headers.len() + checked.len() >= MAX_PENDING_HEADERS {
+                break;
+            }
+            if header.difficulty > blockchain.pow_limit() {
+                return Err(BlockError::TargetAboveLimit);
+            }
+            if hash > header.difficulty {
+                return Err(BlockError::InsufficientWork);
+            }
+            let pending = |h: &H256| checked.get(h).or_else(|| self.headers.get(h));
+            let (parent_header, parent_height, parent_work) = match pending(&header.parent) {
+                Some(p) => (&p.header, p.height, p.work),
+                None => match blockchain.get_header(&header.parent) {
+                    Some(h) => (
+                        h,
+                        blockchain.height(&header.parent).unwrap(),
+                        blockchain.cumulative_work(&header.parent).unwrap(),
+                    ),
+                    None => return Err(BlockError::UnknownParent(header.parent)),
+                },
+            };
+            let expected = blockchain
+                .difficulty_after_with(parent_header, parent_height, |h| {
+                    pending(h).map(|p| &p.header).or_else(|| blockchain.get_header(h))
+                })
+                .ok_or(BlockError::UnknownParent(header.parent))?;
+            if header.difficulty != expected {
+                return Err(BlockError::WrongDifficulty { expected, actual: header.difficulty });
+            }
+            let work = parent_work
+                .checked_add(&block_work(&header.difficulty))
+                .expect("Cumulative work overflow");
+            checked.insert(
+                hash,
+                PendingHeader {
+                    header: header.clone(),
+                    height: parent_height + 1,
+                    work,
+                    best_work: work,
+                },
+            );
+            order.push(hash);
+        }
+        let new = order.len();
+        for hash in order.iter() {
+            self.headers.insert(*hash, checked.remove(hash).unwrap());
+            self.queue.push_back(*hash);
+        }
+        // children first, so the walk up stops at the headers a later child already reached
+        for hash in order.iter().rev() {
+            let best_work = self.headers[hash].best_work;
+            let mut parent = self.headers[hash].header.parent;
+            while let Some(p) = self.headers.get_mut(&parent) {
+                if p.best_work >= best_work {
+                    break;
+                }
+                p.best_work = best_work;
+                parent = p.header.parent;
+            }
+        }
+        Ok(new)
+    }
+
+    /// Forget the headers whose chains have no more work than the tip, to make room
+    fn trim(&mut self, blockchain: &Blockchain) {
+        let tip_work = blockchain.cumulative_work(&blockchain.tip()).unwrap();
+        let in_flight = &self.in_flight;
+        // the descendants of a header have at most its best work, so no branch is cut in two
+        self.headers.retain(|h, p| p.best_work > tip_work || in_flight.contains_key(h));
+        let headers = &self.headers;
+        self.queue.retain(|h| headers.contains_key(h));
+    }
+
+    /// Forget a block once it is in the blockchain
+    pub fn received(&mut self, hash: &H256) {
+        self.headers.remove(hash);
+        self.in_flight.remove(hash);
+    }
+
+    /// Forget a block that was rejected, with the headers descending from it
+    pub fn rejected(&mut self, hash: &H256) {
+        self.drop_branch(*hash);
+    }
+
+    /// Forget `hash` and the pending headers descending from it
+    fn drop_branch(&mut self, hash: H256) {
+        let mut dropped = HashSet::new();
+        dropped.insert(hash);
+        // parents come before their children in the queue
+        for h in self.queue.iter() {
+            if self.headers.get(h).is_some_and(|p| dropped.contains(&p.header.parent)) {
+                dropped.insert(*h);
+            }
+        }
+        for h in dropped.iter() {
+            self.headers.remove(h);
+            self.in_flight.remove(h);
+        }
+        let headers = &self.headers;
+        self.queue.retain(|h| headers.contains_key(h));
+    }
+
+    /// Assign queued blocks to the peers with free download slots, taking back the requests that
+    /// timed out. Returns the peers to send `GetBlocks` to, with the hashes to ask for.
+    pub fn schedule(&mut self, blockchain: &Blockchain) -> Vec<(peer::Handle, Vec<H256>)> {
+        self.schedule_at(blockchain, Instant::now())
+    }
+
+    fn schedule_at(&mut self, blockchain: &Blockchain, now: Instant) -> Vec<(peer::Handle, Vec<H256>)> {
+        while let Some(hash) = self.queue.front() {
+            if self.headers.contains_key(hash) {
+                break;
+            }
+            self.queue.pop_front();
+        }
+        // blocks received out of order leave entries behind the front
+        if self.queue.len() > 2 * MAX_PENDING_HEADERS {
+            let headers = &self.headers;
+            self.queue.retain(|h| headers.contains_key(h));
+        }
+        // a peer that lets a request time out is not asked again until it sends headers again,
+        // the block goes back to the queue for the other peers
+        let timed_out: Vec<(H256, SocketAddr)> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (_, asked))| now.saturating_duration_since(*asked) >= BLOCK_DOWNLOAD_TIMEOUT)
+            .map(|(hash, (addr, _))| (*hash, *addr))
+            .collect();
+        for (hash, addr) in timed_out.iter() {
+            self.peers.remove(addr);
+            self.in_flight.remove(hash);
+        }
+        // with no peer left to ask, the branch is dropped until some peer announces it again
+        if self.peers.is_empty() {
+            for (hash, _) in timed_out {
+                self.drop_branch(hash);
+            }
+        }
+
+        let mut free: HashMap<SocketAddr, usize> = self
+            .peers
+            .keys()
+            .map(|addr| (*addr, MAX_BLOCKS_IN_FLIGHT_PER_PEER))
+            .collect();
+        for (addr, _) in self.in_flight.values() {
+            if let Some(slots) = free.get_mut(addr) {
+                *slots = slots.saturating_sub(1);
+            }
+        }
+        let tip_work = blockchain.cumulative_work(&blockchain.tip()).unwrap();
+        let mut requests: HashMap<SocketAddr, Vec<H256>> = HashMap::new();
+        let mut peers = free.into_iter().filter(|(_, slots)| *slots > 0).collect::<Vec<_>>();
+        // hand out consecutive blocks round robin, so every peer works near the front of the queue
+        let mut next_peer = 0;
+        for hash in self.queue.iter() {
+            if peers.is_empty() {
+                break;
+            }
+            // a chain with no more work than the tip would never become the tip
+            let wanted = self.headers.get(hash).is_some_and(|p| p.best_work > tip_work);
+            if !wanted || self.in_flight.contains_key(hash) {
+                continue;
+            }
+            next_peer %= peers.len();
+            let (addr, slots) = &mut peers[next_peer];
+            requests.entry(*addr).or_default().push(*hash);
+            self.in_flight.insert(*hash, (*addr, now));
+            *slots -= 1;
+            if *slots == 0 {
+                peers.swap_remove(next_peer);
+            } else {
+                next_peer += 1;
+            }
+        }
+        requests
+            .into_iter()
+            .map(|(addr, hashes)| (self.peers[&addr].clone(), hashes))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::{generate_random_block, Block};
+
+    fn mine(mut block: Block) -> Block {
+        while block.hash() > block.header.difficulty {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        block
+    }
+
+    /// Mine `n` blocks on top of `parent`, at the difficulty the retargeting rule gives
+    fn mine_chain(blockchain: &Blockchain, parent: H256, n: usize) -> Vec<Block> {
+        let mut headers: HashMap<H256, Header> = HashMap::new();
+        let mut parent_header = blockchain.get_header(&parent).unwrap().clone();
+        let height = blockchain.height(&parent).unwrap();
+        let mut blocks = vec![];
+        for parent_height in height..height + n as u64 {
+            let mut block = generate_random_block(&parent_header.hash());
+            block.header.difficulty = blockchain
+                .difficulty_after_with(&parent_header, parent_height, |h| {
+                    headers.get(h).or_else(|| blockchain.get_header(h))
+                })
+                .unwrap();
+            let block = mine(block);
+            headers.insert(block.hash(), block.header.clone());
+            parent_header = block.header.clone();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    fn headers_of(blocks: &[Block]) -> Vec<Header> {
+        blocks.iter().map(|b| b.header.clone()).collect()
+    }
+
+    #[test]
+    fn queue_headers_for_download() {
+        let blockchain = Blockchain::new();
+        // long enough to cross a retarget
+        let blocks = mine_chain(&blockchain, blockchain.tip(), 20);
+        let headers = headers_of(&blocks);
+
+        let mut sync = HeaderSync::new();
+        // a header that does not link to anything known is refused, with the ones after it
+        assert!(matches!(sync.accept_headers(&headers[1..], &blockchain), Err(BlockError::UnknownParent(_))));
+        assert_eq!(sync.accept_headers(&headers, &blockchain), Ok(20));
+        assert_eq!(sync.accept_headers(&headers, &blockchain), Ok(0));
+        let mut tampered = generate_random_block(&blocks[19].hash()).header;
+        tampered.difficulty = [0u8; 32].into();
+        assert_eq!(sync.accept_headers(&[tampered], &blockchain), Err(BlockError::InsufficientWork));
+
+        let (peer_1, _receiver_1) = peer::Handle::test_handle();
+        sync.add_peer(&peer_1);
+        let requests = sync.schedule(&blockchain);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].1, blocks[..16].iter().map(|b| b.hash()).collect::<Vec<H256>>());
+        // the first peer is busy, so nothing more is requested until it delivers
+        assert!(sync.schedule(&blockchain).is_empty());
+        sync.received(&blocks[0].hash());
+        let requests = sync.schedule(&blockchain);
+        assert_eq!(requests[0].1, vec![blocks[16].hash()]);
+        assert!(sync.is_pending(&blocks[1].hash()));
+        assert!(!sync.is_pending(&blocks[0].hash()));
+    }
+
+    #[test]
+    fn ask_another_peer_after_timeout() {
+        let blockchain = Blockchain::new();
+        let blocks = mine_chain(&blockchain, blockchain.tip(), 3);
+        let mut sync = HeaderSync::new();
+        assert_eq!(sync.accept_headers(&headers_of(&blocks), &blockchain), Ok(3));
+
+        let (slow, _slow_receiver) = peer::Handle::test_handle_at("127.0.0.1:6001".parse().unwrap());
+        sync.add_peer(&slow);
+        let start = Instant::now();
+        let requests = sync.schedule_at(&blockchain, start);
+        assert_eq!(requests.len(), 1);
+        let (fast, _fast_receiver) = peer::Handle::test_handle_at("127.0.0.1:6002".parse().unwrap());
+        sync.add_peer(&fast);
+        assert!(sync.schedule_at(&blockchain, start).is_empty());
+
+        // the first peer let its requests time out, the same blocks go to the other one
+        let requests = sync.schedule_at(&blockchain, start + BLOCK_DOWNLOAD_TIMEOUT);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0.addr(), fast.addr());
+        assert_eq!(requests[0].1, blocks.iter().map(|b| b.hash()).collect::<Vec<H256>>());
+        assert!(blocks.iter().all(|b| sync.is_pending(&b.hash())));
+
+        // a peer that disconnects gives its blocks back right away
+        sync.remove_peer(fast.addr());
+        let (next, _next_receiver) = peer::Handle::test_handle_at("127.0.0.1:6003".parse().unwrap());
+        sync.add_peer(&next);
+        let requests = sync.schedule_at(&blockchain, start + BLOCK_DOWNLOAD_TIMEOUT);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0.addr(), next.addr());
+        assert_eq!(requests[0].1.len(), 3);
+    }
+
+    #[test]
+    fn trim_junk_header_chains() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        for block in mine_chain(&blockchain, genesis, 3) {
+            blockchain.insert(&block);
+        }
+        let mut sync = HeaderSync::new();
+
+        // a header at another difficulty than the chain requires
+        let mut junk = generate_random_block(&blockchain.tip());
+        let mut target: [u8; 32] = blockchain.pow_limit().into();
+        target[0] /= 2;
+        junk.header.difficulty = target.into();
+        let junk = mine(junk);
+        assert!(matches!(
+            sync.accept_headers(&[junk.header], &blockchain),
+            Err(BlockError::WrongDifficulty { .. })
+        ));
+
+        // a valid fork is kept, but not downloaded while it has less work than the tip
+        let fork = mine_chain(&blockchain, genesis, 4);
+        let headers = headers_of(&fork);
+        assert_eq!(sync.accept_headers(&headers[..2], &blockchain), Ok(2));
+        assert!(sync.is_pending(&fork[0].hash()));
+        let (peer_1, _receiver_1) = peer::Handle::test_handle();
+        sync.add_peer(&peer_1);
+        let start = Instant::now();
+        assert!(sync.schedule_at(&blockchain, start).is_empty());
+
+        // once the rest of the fork arrives it has more work, the whole of it is downloaded
+        assert_eq!(sync.accept_headers(&headers[2..], &blockchain), Ok(2));
+        let requests = sync.schedule_at(&blockchain, start);
+        assert_eq!(requests[0].1, fork.iter().map(|b| b.hash()).collect::<Vec<H256>>());
+
+        // its blocks never arrive, and with no other peer to ask, the fork is dropped
+        assert!(sync.schedule_at(&blockchain, start + BLOCK_DOWNLOAD_TIMEOUT).is_empty());
+        assert!(fork.iter().all(|b| !sync.is_pending(&b.hash())));
+        assert!(sync.headers.is_empty() && sync.queue.is_empty() && sync.in_flight.is_empty());
+
+        // lighter forks make room when the headers fill up
+        let short_fork = mine_chain(&blockchain, genesis, 2);
+        assert_eq!(sync.accept_headers(&headers_of(&short_fork), &blockchain), Ok(2));
+        sync.trim(&blockchain);
+        assert!(sync.headers.is_empty() && sync.queue.is_empty() && sync.in_flight.is_empty());
+    }
+}
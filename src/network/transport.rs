@@ -0,0 +1,344 @@
+use crate::types::key_pair;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::digest::{Context, SHA256};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, ED25519};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Bytes a sealed frame has over its payload, the authentication tag.
+pub const SEAL_OVERHEAD: usize = 16;
+/// Mixed into the transcript, so keys and signatures are only good for this protocol.
+const PROTOCOL_NAME: &[u8] = b"bitcoin-p2p X25519 Ed25519 ChaCha20-Poly1305 SHA256 1";
+const INITIATOR: &[u8] = b"initiator";
+const RESPONDER: &[u8] = b"responder";
+
+/// Whether to encrypt peer connections.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    /// Always talk in the clear
+    Off,
+    /// Encrypt when the peer supports it
+    Preferred,
+    /// Refuse peers that do not encrypt
+    Required,
+}
+
+impl FromStr for Encryption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Encryption::Off),
+            "preferred" => Ok(Encryption::Preferred),
+            "required" => Ok(Encryption::Required),
+            _ => Err(format!("unknown encryption mode {}, expected off, preferred or required", s)),
+        }
+    }
+}
+
+/// Reasons for dropping a connection while setting up or using the transport.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportError {
+    /// The peer's hello does not decode
+    MalformedHello,
+    /// One side requires encryption and the other has it off
+    EncryptionRefused,
+    /// The peer's ephemeral key is not a valid X25519 key
+    KeyAgreement,
+    /// A frame does not open, it was altered, replayed or not sealed with the session key
+    Decryption,
+    /// The peer did not prove the identity it claims
+    BadIdentity,
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TransportError::MalformedHello => write!(f, "malformed transport hello"),
+            TransportError::EncryptionRefused => write!(f, "encryption required by one side and off on the other"),
+            TransportError::KeyAgreement => write!(f, "invalid ephemeral key"),
+            TransportError::Decryption => write!(f, "frame failed to decrypt"),
+            TransportError::BadIdentity => write!(f, "invalid identity proof"),
+        }
+    }
+}
+
+/// Public identity key of a node, proven on encrypted connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId([u8; 32]);
+
+impl std::fmt::Display for PeerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// Long term key of this node, which signs the transcript of every encrypted handshake.
+#[derive(Debug)]
+pub struct Identity {
+    key: Ed25519KeyPair,
+}
+
+impl Identity {
+    /// Generate an identity that only lasts as long as the process
+    pub fn generate() -> Self {
+        Identity { key: key_pair::random() }
+    }
+
+    /// Load the identity key stored at `path`, creating it with a new key if there is none
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let pkcs8 = match fs::read(path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+                let mut options = fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                // the key is private to the node
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                options.open(path)?.write_all(pkcs8.as_ref())?;
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(e),
+        };
+        let key = Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid identity key: {}", e)))?;
+        Ok(Identity { key })
+    }
+
+    pub fn id(&self) -> PeerId {
+        PeerId(self.key.public_key().as_ref().try_into().unwrap())
+    }
+}
+
+/// First frame from each side, always in the clear, to agree on the transport.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Hello {
+    encryption: Encryption,
+    /// X25519 public key for this connection, empty with encryption off
+    ephemeral: Vec<u8>,
+}
+
+/// First sealed frame from each side, binding its identity to the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Auth {
+    identity: Vec<u8>,
+    /// Signature of the role of the signer followed by the transcript hash
+    signature: Vec<u8>,
+}
+
+/// Our side of the transport handshake, until the peer's hello arrives.
+///
+/// Both sides send a hello with an ephemeral X25519 key. If they agree to encrypt, each derives
+/// a key per direction from the shared secret and the hash of both hellos, then sends, sealed,
+/// its identity key and its signature of that hash. Each connection gets fresh keys, and a peer
+/// can only claim an identity whose key it holds.
+pub struct Handshake {
+    encryption: Encryption,
+    initiator: bool,
+    ephemeral: Option<EphemeralPrivateKey>,
+    hello: Vec<u8>,
+}
+
+/// Agreed encrypted transport: the halves for each direction, and the sealed identity proof to
+/// send first.
+pub struct Session {
+    pub sealer: Sealer,
+    pub opener: Opener,
+    pub auth: Vec<u8>,
+}
+
+impl Handshake {
+    /// Start a handshake, `initiator` being the side that opened the connection. Also returns
+    /// the hello to send.
+    pub fn new(encryption: Encryption, initiator: bool) -> (Self, Vec<u8>) {
+        let (ephemeral, public) = match encryption {
+            Encryption::Off => (None, vec![]),
+            _ => {
+                let key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new()).unwrap();
+                let public = key.compute_public_key().unwrap().as_ref().to_vec();
+                (Some(key), public)
+            }
+        };
+        let hello = bincode::serialize(&Hello { encryption, ephemeral: public }).unwrap();
+        let handshake = Handshake {
+            encryption,
+            initiator,
+            ephemeral,
+            hello: hello.clone(),
+        };
+        (handshake, hello)
+    }
+
+    /// Agree on the transport from the peer's hello: `None` to talk in the clear, or the
+    /// encrypted session
+    pub fn agree(self, their_hello: &[u8], identity: &Identity) -> Result<Option<Session>, TransportError> {
+        let theirs: Hello = bincode::deserialize(their_hello).map_err(|_| TransportError::MalformedHello)?;
+        let ephemeral = match (self.ephemeral, theirs.encryption) {
+            (Some(ephemeral), Encryption::Preferred) | (Some(ephemeral), Encryption::Required) => ephemeral,
+            _ if self.encryption == Encryption::Required || theirs.encryption == Encryption::Required => {
+                return Err(TransportError::EncryptionRefused);
+            }
+            _ => return Ok(None),
+        };
+
+        let (initiator_hello, responder_hello) = match self.initiator {
+            true => (&self.hello[..], their_hello),
+            false => (their_hello, &self.hello[..]),
+        };
+        let mut ctx = Context::new(&SHA256);
+        ctx.update(PROTOCOL_NAME);
+        ctx.update(initiator_hello);
+        ctx.update(responder_hello);
+        let transcript = ctx.finish();
+
+        let their_key = UnparsedPublicKey::new(&X25519, theirs.ephemeral);
+        let (initiator_key, responder_key) =
+            agreement::agree_ephemeral(ephemeral, &their_key, TransportError::KeyAgreement, |shared| {
+                let prk = Salt::new(HKDF_SHA256, transcript.as_ref()).extract(shared);
+                let key = |role: &[u8]| {
+                    LessSafeKey::new(UnboundKey::from(prk.expand(&[role], &CHACHA20_POLY1305).unwrap()))
+                };
+                Ok((key(INITIATOR), key(RESPONDER)))
+            })?;
+        let (our_role, their_role, sealing_key, opening_key) = match self.initiator {
+            true => (INITIATOR, RESPONDER, initiator_key, responder_key),
+            false => (RESPONDER, INITIATOR, responder_key, initiator_key),
+        };
+
+        let auth = Auth {
+            identity: identity.key.public_key().as_ref().to_vec(),
+            signature: identity.key.sign(&[our_role, transcript.as_ref()].concat()).as_ref().to_vec(),
+        };
+        let mut sealer = Sealer { key: sealing_key, sequence: 0 };
+        let auth = sealer.seal(&bincode::serialize(&auth).unwrap());
+        let opener = Opener {
+            key: opening_key,
+            sequence: 0,
+            expected_signed: [their_role, transcript.as_ref()].concat(),
+        };
+        Ok(Some(Session { sealer, opener, auth }))
+    }
+}
+
+/// The sequence number of a frame, as its nonce. Each direction has its own key, so a nonce is
+/// never used twice with the same key.
+fn nonce(sequence: u64) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[NONCE_LEN - 8..].copy_from_slice(&sequence.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Seals the frames we send.
+pub struct Sealer {
+    key: LessSafeKey,
+    sequence: u64,
+}
+
+impl Sealer {
+    pub fn seal(&mut self, payload: &[u8]) -> Vec<u8> {
+        let mut frame = payload.to_vec();
+        self.key
+            .seal_in_place_append_tag(nonce(self.sequence), Aad::empty(), &mut frame)
+            .unwrap();
+        self.sequence += 1;
+        frame
+    }
+}
+
+/// Opens the frames the peer sends, which must arrive in order.
+pub struct Opener {
+    key: LessSafeKey,
+    sequence: u64,
+    /// What the peer's identity proof must be a signature of
+    expected_signed: Vec<u8>,
+}
+
+impl Opener {
+    pub fn open(&mut self, mut frame: Vec<u8>) -> Result<Vec<u8>, TransportError> {
+        let len = self
+            .key
+            .open_in_place(nonce(self.sequence), Aad::empty(), &mut frame)
+            .map_err(|_| TransportError::Decryption)?
+            .len();
+        self.sequence += 1;
+        frame.truncate(len);
+        Ok(frame)
+    }
+
+    /// Check the peer's identity proof, its first frame once opened, and get its identity
+    pub fn authenticate(&self, payload: &[u8]) -> Result<PeerId, TransportError> {
+        let auth: Auth = bincode::deserialize(payload).map_err(|_| TransportError::BadIdentity)?;
+        signature::UnparsedPublicKey::new(&ED25519, &auth.identity)
+            .verify(&self.expected_signed, &auth.signature)
+            .map_err(|_| TransportError::BadIdentity)?;
+        let id = auth.identity.try_into().map_err(|_| TransportError::BadIdentity)?;
+        Ok(PeerId(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(initiator: Encryption, responder: Encryption) -> (Result<Option<Session>, TransportError>, Result<Option<Session>, TransportError>) {
+        let (a, a_hello) = Handshake::new(initiator, true);
+        let (b, b_hello) = Handshake::new(responder, false);
+        (a.agree(&b_hello, &Identity::generate()), b.agree(&a_hello, &Identity::generate()))
+    }
+
+    #[test]
+    fn negotiate_encryption() {
+        let (a, b) = connect(Encryption::Preferred, Encryption::Required);
+        assert!(a.unwrap().is_some() && b.unwrap().is_some());
+        let (a, b) = connect(Encryption::Preferred, Encryption::Off);
+        assert!(a.unwrap().is_none() && b.unwrap().is_none());
+        let (a, b) = connect(Encryption::Off, Encryption::Required);
+        assert_eq!(a.err(), Some(TransportError::EncryptionRefused));
+        assert_eq!(b.err(), Some(TransportError::EncryptionRefused));
+    }
+
+    #[test]
+    fn authenticate_and_seal() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let (a, a_hello) = Handshake::new(Encryption::Preferred, true);
+        let (b, b_hello) = Handshake::new(Encryption::Preferred, false);
+        let mut a = a.agree(&b_hello, &alice).unwrap().unwrap();
+        let mut b = b.agree(&a_hello, &bob).unwrap().unwrap();
+
+        let a_auth = b.opener.open(a.auth.clone()).unwrap();
+        assert_eq!(b.opener.authenticate(&a_auth), Ok(alice.id()));
+        let b_auth = a.opener.open(b.auth).unwrap();
+        assert_eq!(a.opener.authenticate(&b_auth), Ok(bob.id()));
+        // a proof is only good for its own handshake and direction
+        assert_eq!(a.opener.authenticate(&a_auth), Err(TransportError::BadIdentity));
+
+        let frame = a.sealer.seal(b"ping");
+        assert_eq!(frame.len(), 4 + SEAL_OVERHEAD);
+        assert_eq!(b.opener.open(frame.clone()).unwrap(), b"ping");
+        // replayed
+        assert_eq!(b.opener.open(frame), Err(TransportError::Decryption));
+        let mut altered = a.sealer.seal(b"pong");
+        altered[0] ^= 1;
+        assert_eq!(b.opener.open(altered), Err(TransportError::Decryption));
+    }
+
+    #[test]
+    fn persist_identity() {
+        let path = std::env::temp_dir().join(format!("identity-{}.pk8", rand::random::<u64>()));
+        let created = Identity::open(&path).unwrap();
+        assert_eq!(Identity::open(&path).unwrap().id(), created.id());
+        fs::write(&path, b"not a key").unwrap();
+        assert!(Identity::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
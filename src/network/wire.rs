@@ -0,0 +1,381 @@
+use super::message::Message;
+use crate::types::hash::H256;
+use ring::digest::{digest, SHA256};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::TryInto;
+
+/// Bytes that start every message, telling networks apart.
+pub type Magic = [u8; 4];
+
+/// Version of the payload encoding. A decoder refuses versions it does not know, so changing
+/// how a payload is laid out means a new version.
+pub const WIRE_VERSION: u8 = 1;
+/// Size of the header before the payload: magic, command, version and checksum.
+pub const HEADER_LEN: usize = 4 + 2 + 1 + 4;
+
+// Command of each message type. They are part of the protocol: a new message type gets a new
+// command, and the command of a removed one is never reused.
+const PING: u16 = 1;
+const PONG: u16 = 2;
+const NEW_BLOCK_HASHES: u16 = 3;
+const GET_BLOCKS: u16 = 4;
+const BLOCKS: u16 = 5;
+const NEW_TRANSACTION_HASHES: u16 = 6;
+const GET_TRANSACTIONS: u16 = 7;
+const TRANSACTIONS: u16 = 8;
+const GET_HEADERS: u16 = 9;
+const HEADERS: u16 = 10;
+const VERSION: u16 = 11;
+const VERACK: u16 = 12;
+const GET_ADDR: u16 = 13;
+const ADDR: u16 = 14;
+const COMPACT_BLOCK: u16 = 15;
+const GET_BLOCK_TXN: u16 = 16;
+const BLOCK_TXN: u16 = 17;
+
+/// Reasons for refusing a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    /// Shorter than the header
+    Truncated,
+    /// From another network
+    WrongMagic(Magic),
+    /// A payload encoding this node does not know, from a newer peer
+    UnsupportedVersion(u8),
+    /// A message type this node does not know, from a newer peer
+    UnknownCommand(u16),
+    /// The payload does not match its checksum
+    BadChecksum,
+    /// The payload does not decode as its message type
+    Malformed,
+}
+
+impl WireError {
+    /// Whether the message may be valid for a newer version of the protocol, in which case the
+    /// peer is not at fault
+    pub fn is_unknown(&self) -> bool {
+        matches!(self, WireError::UnsupportedVersion(_) | WireError::UnknownCommand(_))
+    }
+}
+
+impl std::fmt::Display for WireError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WireError::Truncated => write!(f, "message shorter than its header"),
+            WireError::WrongMagic(m) => write!(f, "magic {} of another network", hex::encode(m)),
+            WireError::UnsupportedVersion(v) => write!(f, "unsupported wire version {}", v),
+            WireError::UnknownCommand(c) => write!(f, "unknown command {}", c),
+            WireError::BadChecksum => write!(f, "payload checksum mismatch"),
+            WireError::Malformed => write!(f, "malformed payload"),
+        }
+    }
+}
+
+/// Get the magic of the network with the given genesis block, so nodes on different chains
+/// never take each other's messages.
+pub fn network_magic(genesis: &H256) -> Magic {
+    digest(&SHA256, genesis.as_ref()).as_ref()[..4].try_into().unwrap()
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    digest(&SHA256, payload).as_ref()[..4].try_into().unwrap()
+}
+
+fn encode_payload<T: Serialize + ?Sized>(value: &T) -> Vec<u8> {
+    bincode::serialize(value).unwrap()
+}
+
+fn decode_payload<T: Serialize + DeserializeOwned>(payload: &[u8]) -> Result<T, WireError> {
+    let value: T = bincode::deserialize(payload).map_err(|_| WireError::Malformed)?;
+    // trailing bytes would let the same message be sent in many forms
+    if bincode::serialized_size(&value).map_err(|_| WireError::Malformed)? != payload.len() as u64 {
+        return Err(WireError::Malformed);
+    }
+    Ok(value)
+}
+
+/// Encode a message for the network with `magic`.
+///
+/// The header is the magic, the command as a big endian `u16`, the wire version, and the first
+/// 4 bytes of the SHA256 of the payload. The payload holds the fields of the message, encoded
+/// with bincode. The transport frame gives the length.
+pub fn encode(magic: Magic, msg: &Message) -> Vec<u8> {
+    let (command, payload) = match msg {
+        Message::Ping(nonce) => (PING, encode_payload(nonce)),
+        Message::Pong(nonce) => (PONG, encode_payload(nonce)),
+        Message::NewBlockHashes(hashes) => (NEW_BLOCK_HASHES, encode_payload(hashes)),
+        Message::GetBlocks(hashes) => (GET_BLOCKS, encode_payload(hashes)),
+        Message::Blocks(blocks) => (BLOCKS, encode_payload(blocks)),
+        Message::NewTransactionHashes(hashes) => (NEW_TRANSACTION_HASHES, encode_payload(hashes)),
+        Message::GetTransactions(hashes) => (GET_TRANSACTIONS, encode_payload(hashes)),
+        Message::Transactions(txs) => (TRANSACTIONS, encode_payload(txs)),
+        Message::GetHeaders(locator) => (GET_HEADERS, encode_payload(locator)),
+        Message::Headers(headers) => (HEADERS, encode_payload(headers)),
+        Message::Version(version) => (VERSION, encode_payload(version)),
+        Message::VerAck => (VERACK, vec![]),
+        Message::GetAddr => (GET_ADDR, vec![]),
+        Message::Addr(addrs) => (ADDR, encode_payload(addrs)),
+        Message::CompactBlock(compact) => (COMPACT_BLOCK, encode_payload(compact)),
+        Message::GetBlockTxn(hash, indexes) => (GET_BLOCK_TXN, encode_payload(&(hash, indexes))),
+        Message::BlockTxn(hash, txs) => (BLOCK_TXN, encode_payload(&(hash, txs))),
+    };
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&magic);
+    bytes.extend_from_slice(&command.to_be_bytes());
+    bytes.push(WIRE_VERSION);
+    bytes.extend_from_slice(&checksum(&payload));
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+/// Decode a message encoded by `encode` for the network with `magic`
+pub fn decode(magic: Magic, bytes: &[u8]) -> Result<Message, WireError> {
+    if bytes.len() < HEADER_LEN {
+        return Err(WireError::Truncated);
+    }
+    let (header, payload) = bytes.split_at(HEADER_LEN);
+    let their_magic: Magic = header[..4].try_into().unwrap();
+    if their_magic != magic {
+        return Err(WireError::WrongMagic(their_magic));
+    }
+    let command = u16::from_be_bytes(header[4..6].try_into().unwrap());
+    if header[6] != WIRE_VERSION {
+        return Err(WireError::UnsupportedVersion(header[6]));
+    }
+    if header[7..] != checksum(payload) {
+        return Err(WireError::BadChecksum);
+    }
+    let msg = match command {
+        PING => Message::Ping(decode_payload(payload)?),
+        PONG => Message::Pong(decode_payload(payload)?),
+        NEW_BLOCK_HASHES => Message::NewBlockHashes(decode_payload(payload)?),
+        GET_BLOCKS => Message::GetBlocks(decode_payload(payload)?),
+        BLOCKS => Message::Blocks(decode_payload(payload)?),
+        NEW_TRANSACTION_HASHES => Message::NewTransactionHashes(decode_payload(payload)?),
+        GET_TRANSACTIONS => Message::GetTransactions(decode_payload(payload)?),
+        TRANSACTIONS => Message::Transactions(decode_payload(payload)?),
+        GET_HEADERS => Message::GetHeaders(decode_payload(payload)?),
+        HEADERS => Message::Headers(decode_payload(payload)?),
+        VERSION => Message::Version(decode_payload(payload)?),
+        VERACK | GET_ADDR if !payload.is_empty() => return Err(WireError::Malformed),
+        VERACK => Message::VerAck,
+        GET_ADDR => Message::GetAddr,
+        ADDR => Message::Addr(decode_payload(payload)?),
+        COMPACT_BLOCK => Message::CompactBlock(decode_payload(payload)?),
+        GET_BLOCK_TXN => {
+            let (hash, indexes) = decode_payload(payload)?;
+            Message::GetBlockTxn(hash, indexes)
+        }
+        BLOCK_TXN => {
+            let (hash, txs) = decode_payload(payload)?;
+            Message::BlockTxn(hash, txs)
+        }
+        _ => return Err(WireError::UnknownCommand(command)),
+    };
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::compact::CompactBlock;
+    use crate::network::message::Version;
+    use crate::types::block::{Block, Content, Header};
+    use crate::types::transaction::{Coinbase, SignedTransaction, Transaction};
+
+    const MAGIC: Magic = *b"test";
+
+    fn hash(byte: u8) -> H256 {
+        [byte; 32].into()
+    }
+
+    fn header() -> Header {
+        Header {
+            parent: hash(0x11),
+            nonce: 0x0102_0304,
+            difficulty: hash(0x22),
+            timestamp: 0x0a0b,
+            merkle_root: hash(0x33),
+        }
+    }
+
+    fn tx() -> SignedTransaction {
+        SignedTransaction {
+            transaction: Transaction {
+                sender: [0x44; 20].into(),
+                acc_nonce: 1,
+                receiver: [0x55; 20].into(),
+                value: 2,
+                fee: 3,
+            },
+            signature: vec![0xaa; 2],
+            public_key: vec![0xbb; 2],
+        }
+    }
+
+    fn messages() -> Vec<Message> {
+        let block = Block {
+            header: header(),
+            content: Content {
+                coinbase: Some(Coinbase { receiver: [0x66; 20].into(), value: 50, height: 7 }),
+                data: vec![],
+            },
+        };
+        let version = Version {
+            version: 1,
+            genesis: hash(0x77),
+            tip: hash(0x88),
+            height: 9,
+            listen_addr: "127.0.0.1:6000".parse().unwrap(),
+        };
+        let compact = CompactBlock { header: header(), coinbase: None, short_ids: vec![0x0102_0304_0506_0708] };
+        vec![
+            Message::Ping("hi".to_string()),
+            Message::Pong("hi".to_string()),
+            Message::NewBlockHashes(vec![hash(0x01)]),
+            Message::GetBlocks(vec![hash(0x02)]),
+            Message::Blocks(vec![block]),
+            Message::NewTransactionHashes(vec![hash(0x03)]),
+            Message::GetTransactions(vec![hash(0x04)]),
+            Message::Transactions(vec![tx()]),
+            Message::GetHeaders(vec![hash(0x05)]),
+            Message::Headers(vec![header()]),
+            Message::Version(version),
+            Message::VerAck,
+            Message::GetAddr,
+            Message::Addr(vec!["127.0.0.1:6000".parse().unwrap()]),
+            Message::CompactBlock(compact),
+            Message::GetBlockTxn(hash(0x06), vec![1, 2]),
+            Message::BlockTxn(hash(0x07), vec![tx()]),
+        ]
+    }
+
+    // magic, command, version, checksum, payload
+    const GOLDEN: [&str; 17] = [
+        // Ping
+        "74657374 0001 01 ea315766 02000000000000006869",
+        // Pong
+        "74657374 0002 01 ea315766 02000000000000006869",
+        // NewBlockHashes
+        concat!(
+            "74657374 0003 01 e30f814f ",
+            "01000000000000000101010101010101010101010101010101010101010101010101010101010101",
+        ),
+        // GetBlocks
+        concat!(
+            "74657374 0004 01 9cb5e496 ",
+            "01000000000000000202020202020202020202020202020202020202020202020202020202020202",
+        ),
+        // Blocks
+        concat!(
+            "74657374 0005 01 7b62203e ",
+            "01000000000000001111111111111111111111111111111111111111111111111111111111111111",
+            "0403020122222222222222222222222222222222222222222222222222222222222222220b0a0000",
+            "00000000000000000000000033333333333333333333333333333333333333333333333333333333",
+            "33333333016666666666666666666666666666666666666666320000000700000000000000000000",
+            "0000000000",
+        ),
+        // NewTransactionHashes
+        concat!(
+            "74657374 0006 01 c706aef2 ",
+            "01000000000000000303030303030303030303030303030303030303030303030303030303030303",
+        ),
+        // GetTransactions
+        concat!(
+            "74657374 0007 01 2535d687 ",
+            "01000000000000000404040404040404040404040404040404040404040404040404040404040404",
+        ),
+        // Transactions
+        concat!(
+            "74657374 0008 01 6c732692 ",
+            "01000000000000004444444444444444444444444444444444444444010000005555555555555555",
+            "55555555555555555555555502000000030000000200000000000000aaaa0200000000000000bbbb",
+        ),
+        // GetHeaders
+        concat!(
+            "74657374 0009 01 a7002743 ",
+            "01000000000000000505050505050505050505050505050505050505050505050505050505050505",
+        ),
+        // Headers
+        concat!(
+            "74657374 000a 01 042bbf7b ",
+            "01000000000000001111111111111111111111111111111111111111111111111111111111111111",
+            "0403020122222222222222222222222222222222222222222222222222222222222222220b0a0000",
+            "00000000000000000000000033333333333333333333333333333333333333333333333333333333",
+            "33333333",
+        ),
+        // Version
+        concat!(
+            "74657374 000b 01 35d359f6 ",
+            "01000000777777777777777777777777777777777777777777777777777777777777777788888888",
+            "88888888888888888888888888888888888888888888888888888888090000000000000000000000",
+            "7f0000017017",
+        ),
+        // VerAck
+        "74657374 000c 01 e3b0c442",
+        // GetAddr
+        "74657374 000d 01 e3b0c442",
+        // Addr
+        "74657374 000e 01 f8641805 0100000000000000000000007f0000017017",
+        // CompactBlock
+        concat!(
+            "74657374 000f 01 4151aa5b ",
+            "11111111111111111111111111111111111111111111111111111111111111110403020122222222",
+            "222222222222222222222222222222222222222222222222222222220b0a00000000000000000000",
+            "00000000333333333333333333333333333333333333333333333333333333333333333300010000",
+            "00000000000807060504030201",
+        ),
+        // GetBlockTxn
+        concat!(
+            "74657374 0010 01 da0e97e3 ",
+            "06060606060606060606060606060606060606060606060606060606060606060200000000000000",
+            "0100000002000000",
+        ),
+        // BlockTxn
+        concat!(
+            "74657374 0011 01 8279700c ",
+            "07070707070707070707070707070707070707070707070707070707070707070100000000000000",
+            "44444444444444444444444444444444444444440100000055555555555555555555555555555555",
+            "5555555502000000030000000200000000000000aaaa0200000000000000bbbb",
+        ),
+    ];
+
+    #[test]
+    fn golden_bytes() {
+        for (msg, golden) in messages().iter().zip(GOLDEN.iter()) {
+            let golden = hex::decode(golden.replace(' ', "")).unwrap();
+            assert_eq!(hex::encode(encode(MAGIC, msg)), hex::encode(&golden), "{}", msg.command());
+            let decoded = decode(MAGIC, &golden).unwrap();
+            assert_eq!(encode(MAGIC, &decoded), golden);
+        }
+        // the magic of the network depends on nothing but the genesis block
+        assert_eq!(network_magic(&crate::blockchain::Blockchain::new().genesis()), [0xec, 0x1a, 0x48, 0xcb]);
+    }
+
+    #[test]
+    fn reject_bad_messages() {
+        let good = encode(MAGIC, &Message::Ping("hi".to_string()));
+        assert_eq!(decode(*b"main", &good).err(), Some(WireError::WrongMagic(MAGIC)));
+        assert_eq!(decode(MAGIC, &good[..HEADER_LEN - 1]).err(), Some(WireError::Truncated));
+
+        let mut altered = good.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert_eq!(decode(MAGIC, &altered).err(), Some(WireError::BadChecksum));
+
+        let mut newer = good.clone();
+        newer[6] = WIRE_VERSION + 1;
+        assert!(decode(MAGIC, &newer).unwrap_err().is_unknown());
+        let mut unknown = good.clone();
+        unknown[4..6].copy_from_slice(&0xffffu16.to_be_bytes());
+        assert_eq!(decode(MAGIC, &unknown).err(), Some(WireError::UnknownCommand(0xffff)));
+
+        // a payload with bytes to spare, under a valid checksum
+        let mut payload = good[HEADER_LEN..].to_vec();
+        payload.push(0);
+        let mut trailing = good[..HEADER_LEN - 4].to_vec();
+        trailing.extend_from_slice(&checksum(&payload));
+        trailing.extend_from_slice(&payload);
+        assert_eq!(decode(MAGIC, &trailing).err(), Some(WireError::Malformed));
+    }
+}
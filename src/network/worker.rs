@@ -1,50 +1,108 @@
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
+use super::addr_book::MAX_ADDRS_PER_MESSAGE;
+use super::compact::{CompactBlock, PartialBlock};
+use super::inventory::Requests;
 use super::message::Message;
 use super::peer;
 use super::server::Handle as ServerHandle;
+use super::sync::{HeaderSync, MAX_HEADERS};
+use super::wire;
 use crate::types::hash::{H256, Hashable};
 use log::{debug, warn, error};
+use std::net::SocketAddr;
 use std::thread;
+use std::time::{Duration, Instant};
 use crate::{Blockchain, StatePerBlock};
+use crate::blockchain::orphan::OrphanPool;
 use crate::types::block::{Block};
 use crate::types::transaction::{self, Transaction, SignedTransaction};
-use crate::types::mempool::Mempool;
+use crate::types::mempool::{Mempool, MempoolError};
+use crate::validation::{validate_block, validate_header, BlockError};
 
 #[cfg(any(test,test_utilities))]
 use super::peer::TestReceiver as PeerTestReceiver;
 #[cfg(any(test,test_utilities))]
 use super::server::TestReceiver as ServerTestReceiver;
+
+/// Misbehavior points for a message that does not decode.
+const MALFORMED_MESSAGE_PENALTY: u32 = 20;
+/// Misbehavior points for a message over its size limit.
+const OVERSIZED_MESSAGE_PENALTY: u32 = 20;
+/// Misbehavior points for each message over the rate limit, so a peer that keeps flooding ends
+/// up banned while one that bursts now and then does not.
+const RATE_LIMITED_PENALTY: u32 = 1;
+/// Misbehavior points for headers that do not link to the chain, which an honest peer on a
+/// competing branch may send now and then.
+const UNCONNECTED_HEADERS_PENALTY: u32 = 10;
+/// Misbehavior points for a block that breaks consensus rules, enough for a ban by default.
+const INVALID_BLOCK_PENALTY: u32 = 100;
+/// Misbehavior points for a transaction not signed by its sender.
+const INVALID_TRANSACTION_PENALTY: u32 = 10;
+
+/// Misbehavior points for sending a block that fails validation with `e`. Blocks that may
+/// become valid later cost nothing.
+fn block_penalty(e: &BlockError) -> u32 {
+    match e {
+        BlockError::UnknownParent(_) | BlockError::TimestampTooNew => 0,
+        _ => INVALID_BLOCK_PENALTY,
+    }
+}
+
+/// Most new blocks announced as compact blocks at once, more are announced by hash.
+const MAX_COMPACT_ANNOUNCEMENTS: usize = 3;
+/// Most compact blocks waiting for their missing transactions.
+const MAX_PARTIAL_BLOCKS: usize = 16;
+/// How long a compact block waits for its missing transactions.
+const PARTIAL_BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a worker waits for a message before checking for dropped peers anyway.
+const IDLE_WAKEUP_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Clone)]
 pub struct Worker {
     msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
-    orphan_buffer: Arc<Mutex<HashMap<H256, Vec<Block>>>>,
+    orphan_pool: Arc<Mutex<OrphanPool>>,
     mempool: Arc<Mutex<Mempool>>,
     state_per_block: Arc<Mutex<StatePerBlock>>,
+    sync: Arc<Mutex<HeaderSync>>,
+    /// Compact blocks waiting for missing transactions, and when they arrived
+    partial_blocks: Arc<Mutex<HashMap<H256, (PartialBlock, Instant)>>>,
+    /// Blocks and transactions requested after peers announced them
+    block_requests: Arc<Mutex<Requests>>,
+    tx_requests: Arc<Mutex<Requests>>,
+    /// Peers that disconnected or were banned
+    dropped_peers: crossbeam::channel::Receiver<SocketAddr>,
 }
 
 
 impl Worker {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         num_worker: usize,
         msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
         server: &ServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
-        orphan_buffer: &Arc<Mutex<HashMap<H256, Vec<Block>>>>,
+        orphan_pool: &Arc<Mutex<OrphanPool>>,
         mempool: &Arc<Mutex<Mempool>>,
         state_per_block: &Arc<Mutex<StatePerBlock>>,
+        sync: &Arc<Mutex<HeaderSync>>,
     ) -> Self {
         Self {
             msg_chan: msg_src,
             num_worker,
             server: server.clone(),
             blockchain: Arc::clone(blockchain),
-            orphan_buffer: Arc::clone(orphan_buffer),
+            orphan_pool: Arc::clone(orphan_pool),
             mempool: Arc::clone(mempool),
             state_per_block: Arc::clone(state_per_block),
+            sync: Arc::clone(sync),
+            partial_blocks: Arc::new(Mutex::new(HashMap::new())),
+            block_requests: Arc::new(Mutex::new(Requests::new())),
+            tx_requests: Arc::new(Mutex::new(Requests::new())),
+            dropped_peers: server.subscribe_dropped(),
         }
     }
 
@@ -59,29 +117,230 @@ impl Worker {
         }
     }
 
-    fn tx_signature_check(&self, block: Block) -> bool {
-        let txs = block.clone().content.data;
-        let mut is_valid = true;
-        for tx in txs {
-            if !transaction::verify(&tx.transaction, &tx.public_key, &tx.signature) {
-                is_valid = false;
-                break;
+    /// Insert a validated block into the blockchain, record its state, and if the tip moved,
+    /// update the mempool to the new longest chain
+    fn apply_block(&self, blockchain: &mut Blockchain, mempool: &mut Mempool, state_per_block: &mut StatePerBlock, blk: &Block) {
+        let reorg = blockchain.insert(blk);
+        state_per_block.update(blk);
+        if let Some(reorg) = reorg {
+            reorg.update_mempool(blockchain, mempool, state_per_block);
+        }
+    }
+
+    /// Request the blocks the header sync has assigned to peers
+    fn request_blocks(&self, sync: &mut HeaderSync, blockchain: &Blockchain) {
+        for (mut peer, hashes) in sync.schedule(blockchain) {
+            peer.write(Message::GetBlocks(hashes));
+        }
+    }
+
+    /// Hand the blocks the peers that are gone were asked for to the other peers
+    fn release_dropped_peers(&self) {
+        let dropped: Vec<SocketAddr> = self.dropped_peers.try_iter().collect();
+        if dropped.is_empty() {
+            return;
+        }
+        let blockchain = self.blockchain.lock().unwrap();
+        let mut sync = self.sync.lock().unwrap();
+        for addr in dropped {
+            sync.remove_peer(&addr);
+        }
+        self.request_blocks(&mut sync, &blockchain);
+    }
+
+    /// Ask for the missing parents of orphans, from the peers that sent the orphans, leaving out
+    /// the ones the header sync is downloading
+    fn request_parents(&self, parents: Vec<(SocketAddr, H256)>, sync: &HeaderSync) {
+        let mut by_peer: HashMap<SocketAddr, Vec<H256>> = HashMap::new();
+        for (addr, parent) in parents {
+            if !sync.is_pending(&parent) {
+                by_peer.entry(addr).or_default().push(parent);
+            }
+        }
+        for (addr, hashes) in by_peer {
+            self.server.send(addr, Message::GetBlocks(hashes));
+        }
+    }
+
+    /// Ask other peers for the announced blocks and transactions that were not received in time,
+    /// and again for the parents of orphans
+    fn retry_requests(&self) {
+        let parents = self.orphan_pool.lock().unwrap().due_parents();
+        if !parents.is_empty() {
+            self.request_parents(parents, &self.sync.lock().unwrap());
+        }
+        for (mut peer, hashes) in self.block_requests.lock().unwrap().timed_out() {
+            peer.write(Message::GetBlocks(hashes));
+        }
+        for (mut peer, hashes) in self.tx_requests.lock().unwrap().timed_out() {
+            peer.write(Message::GetTransactions(hashes));
+        }
+    }
+
+    /// Validate and insert blocks received from `peer`, keeping the orphans in the orphan pool
+    /// and asking for their parents, then announce the blocks that were added
+    fn process_blocks(&self, peer: &mut peer::Handle, blocks: Vec<Block>) {
+        let mut blockchain = self.blockchain.lock().unwrap();
+        let mut orphan_pool = self.orphan_pool.lock().unwrap();
+        let mut mempool = self.mempool.lock().unwrap();
+        let mut state_per_block = self.state_per_block.lock().unwrap();
+        let mut sync = self.sync.lock().unwrap();
+        let mut new_blocks: Vec<H256> = vec![];
+
+        let mut block_requests = self.block_requests.lock().unwrap();
+        for blk in blocks.iter() {
+            peer.mark_known(blk.hash());
+            block_requests.received(&blk.hash());
+        }
+        drop(block_requests);
+
+        for blk in blocks {
+            let blk_hs = blk.hash();
+            if blockchain.has(blk_hs) {
+                continue;
+            }
+            match validate_block(&blk, &blockchain, &state_per_block) {
+                Ok(()) => {
+                    self.apply_block(&mut blockchain, &mut mempool, &mut state_per_block, &blk);
+                    sync.received(&blk_hs);
+                    new_blocks.push(blk_hs);
+                }
+                // The work is checked, make sure the content belongs to the header before keeping
+                // it until the parent arrives, which is requested below
+                Err(BlockError::UnknownParent(_)) => {
+                    if blk.content.merkle_root() != blk.header.merkle_root {
+                        let e = BlockError::MerkleRootMismatch;
+                        debug!("Rejected block {}: {}", blk_hs, e);
+                        self.server.misbehaving(*peer.addr(), block_penalty(&e), &format!("invalid block: {}", e));
+                        continue;
+                    }
+                    orphan_pool.insert(blk, *peer.addr());
+                }
+                Err(e) => {
+                    debug!("Rejected block {}: {}", blk_hs, e);
+                    sync.rejected(&blk_hs);
+                    let penalty = block_penalty(&e);
+                    if penalty > 0 {
+                        self.server.misbehaving(*peer.addr(), penalty, &format!("invalid block: {}", e));
+                    }
+                }
+            }
+        }
+        // Orphan block handler
+        let mut left_new_blocks: Vec<H256> = new_blocks.clone();
+        while !left_new_blocks.is_empty() {
+            let mut left_new_blocks1: Vec<H256> = vec![];
+            for blk_hs in left_new_blocks {
+                for child in orphan_pool.take_children(&blk_hs) {
+                    match validate_block(&child, &blockchain, &state_per_block) {
+                        Ok(()) => {
+                            self.apply_block(&mut blockchain, &mut mempool, &mut state_per_block, &child);
+                            sync.received(&child.hash());
+                            left_new_blocks1.push(child.hash());
+                            new_blocks.push(child.hash());
+                        }
+                        Err(e) => {
+                            debug!("Rejected orphan block {}: {}", child.hash(), e);
+                            sync.rejected(&child.hash());
+                        }
+                    }
+                }
+            }
+            left_new_blocks = left_new_blocks1;
+        }
+
+        self.request_parents(orphan_pool.due_parents(), &sync);
+        self.request_blocks(&mut sync, &blockchain);
+        drop(sync);
+
+        self.announce_blocks(new_blocks, &blockchain);
+    }
+
+    /// Process a block rebuilt from a compact block, or fetch it in full if the rebuilt block
+    /// does not match its header
+    fn rebuilt_block(&self, peer: &mut peer::Handle, hash: H256, partial: PartialBlock) {
+        match partial.into_block() {
+            Some(block) => {
+                self.server.relay_stats().lock().unwrap().reconstructed_bytes +=
+                    bincode::serialized_size(&block).unwrap();
+                self.process_blocks(peer, vec![block]);
+            }
+            None => {
+                debug!("Compact block {} does not match its header, fetching it in full", hash);
+                self.server.relay_stats().lock().unwrap().fallbacks += 1;
+                peer.write(Message::GetBlocks(vec![hash]));
+            }
+        }
+    }
+
+    /// Announce blocks added to the blockchain: a few as compact blocks, the common case of a new
+    /// tip, more by hash, as happens when catching up
+    fn announce_blocks(&self, hashes: Vec<H256>, blockchain: &Blockchain) {
+        if hashes.is_empty() {
+            return;
+        }
+        if hashes.len() > MAX_COMPACT_ANNOUNCEMENTS {
+            self.server.broadcast(Message::NewBlockHashes(hashes));
+            return;
+        }
+        for hash in hashes {
+            if let Some(block) = blockchain.get(&hash) {
+                self.server.broadcast(Message::CompactBlock(CompactBlock::new(&block)));
             }
         }
-        is_valid
     }
 
     fn worker_loop(&self) {
         loop {
-            let result = smol::block_on(self.msg_chan.recv());
+            // wake up now and then to notice dropped peers when no message arrives
+            let result = smol::block_on(smol::future::or(
+                async { Some(self.msg_chan.recv().await) },
+                async {
+                    smol::Timer::after(IDLE_WAKEUP_INTERVAL).await;
+                    None
+                },
+            ));
+            self.release_dropped_peers();
+            let result = match result {
+                Some(result) => result,
+                None => continue,
+            };
             if let Err(e) = result {
                 error!("network worker terminated {}", e);
                 break;
             }
             let msg = result.unwrap();
             let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let size = msg.len();
+            let msg: Message = match wire::decode(peer.magic(), &msg) {
+                Ok(msg) => msg,
+                // a newer peer may send what this node does not understand yet
+                Err(e) if e.is_unknown() => {
+                    debug!("Ignoring message from {}: {}", peer.addr(), e);
+                    continue;
+                }
+                Err(e) => {
+                    debug!("Malformed message from {}: {}", peer.addr(), e);
+                    self.server.misbehaving(*peer.addr(), MALFORMED_MESSAGE_PENALTY, "malformed message");
+                    continue;
+                }
+            };
+            self.retry_requests();
+            if !peer.allow(&msg) {
+                debug!("Peer {} over the rate limit for {}, dropping the message", peer.addr(), msg.command());
+                self.server.misbehaving(*peer.addr(), RATE_LIMITED_PENALTY, "over the rate limit");
+                continue;
+            }
             match msg {
+                Message::Version(version) => {
+                    // the server hands over the peer's version once the handshake is complete
+                    let blockchain = self.blockchain.lock().unwrap();
+                    if version.height > blockchain.height(&blockchain.tip()).unwrap() {
+                        // catch up with the peer's chain, headers first
+                        peer.write(Message::GetHeaders(blockchain.locator()));
+                    }
+                }
+
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
                     peer.write(Message::Pong(nonce.to_string()));
@@ -94,11 +353,24 @@ impl Worker {
                 Message::NewBlockHashes(hashVec) => {
                     let mut msg = Vec::new();
                     let blockchain = self.blockchain.lock().unwrap();
+                    let orphan_pool = self.orphan_pool.lock().unwrap();
+                    let sync = self.sync.lock().unwrap();
+                    let mut block_requests = self.block_requests.lock().unwrap();
                     for hs in hashVec {
-                        if !blockchain.has(hs) {
+                        peer.mark_known(hs);
+                        // blocks announced by headers are downloaded by the header sync
+                        if !blockchain.has(hs)
+                        && !orphan_pool.contains(&hs)
+                        && !sync.is_pending(&hs)
+                        && block_requests.announced(hs, &peer)
+                    {
                             msg.push(hs);
                         }
                     }
+                    drop(block_requests);
+                    drop(sync);
+                    drop(orphan_pool);
+                    drop(blockchain);
                     if !msg.is_empty() {
                         peer.write(Message::GetBlocks(msg));
                     }
@@ -108,8 +380,9 @@ impl Worker {
                     let mut msg = Vec::new();
                     let blockchain = self.blockchain.lock().unwrap();
                     for hs in hashVec {
-                        if blockchain.hash_block_map.contains_key(&hs) {
-                            msg.push(blockchain.hash_block_map[&hs].clone());
+                        if let Some(blk) = blockchain.get(&hs) {
+                            msg.push(blk);
+                            peer.mark_known(hs);
                         }
                     }
                     if !msg.is_empty() {
@@ -117,103 +390,146 @@ impl Worker {
                     }
                 }
 
-                Message::Blocks(blockVec) => {
-                    let mut blockchain = self.blockchain.lock().unwrap();
-                    let mut orphan_buffer = self.orphan_buffer.lock().unwrap();
-                    let mut mempool = self.mempool.lock().unwrap();
-                    let mut new_blocks: Vec<H256> = vec![];
-                    let mut missing_parents: Vec<H256> = vec![];
-                    // let difficulty = blockchain.get(blockchain.tip()).get_difficulty();
-                    let difficulty = blockchain.hash_block_map[&blockchain.tip()].get_difficulty();
-
-                    for blk in blockVec {
-                        let blk_hs = blk.hash();
-                        // check if block.hash() <= difficulty
-                        if blk_hs > difficulty {
+                Message::Blocks(blocks) => {
+                    let mut stats = self.server.relay_stats().lock().unwrap();
+                    stats.full_blocks += blocks.len() as u64;
+                    stats.full_bytes += size as u64;
+                    drop(stats);
+                    self.process_blocks(&mut peer, blocks);
+                }
+
+                Message::CompactBlock(compact) => {
+                    let hash = compact.hash();
+                    peer.mark_known(hash);
+                    let blockchain = self.blockchain.lock().unwrap();
+                    if blockchain.has(hash)
+                        || self.orphan_pool.lock().unwrap().contains(&hash)
+                        || self.partial_blocks.lock().unwrap().contains_key(&hash)
+                    {
+                        continue;
+                    }
+                    // the work is checked before anything is kept or asked for
+                    let checked = validate_header(&compact.header, &blockchain);
+                    drop(blockchain);
+                    match checked {
+                        Ok(()) => {}
+                        Err(BlockError::UnknownParent(_)) => {
+                            // fetch it in full, which goes through the orphan handling
+                            peer.write(Message::GetBlocks(vec![hash]));
                             continue;
                         }
-
-                        // Parent check
-                        let parent_hs = blk.get_parent();
-
-                        // Check if the block's parent exists local copy of blockchain
-                        if blockchain.has(parent_hs) {
-                            if blk.get_difficulty() == difficulty {
-                                if !blockchain.has(blk_hs) {
-                                    if self.tx_signature_check(blk.clone()) {
-                                        // update blockchain
-                                        blockchain.insert(&blk);
-                                        let mut state_per_block = self.state_per_block.lock().unwrap();
-                                        state_per_block.update(&blk);
-                                        drop(state_per_block);
-                                        new_blocks.push(blk_hs);
-
-                                        // update mempool
-                                        for tx in &blk.content.data {
-                                            mempool.tx_map.remove(&tx.hash());
-                                        }
-                                    }
-                                }
-                            }
-                        } else { // If this check fails, also send GetBlocks message, containing this parent hash
-                            missing_parents.push(parent_hs);
-                            // Prepare for Orphan block handler
-                            let mut children: Vec<Block> = vec![];
-                            if orphan_buffer.contains_key(&parent_hs) {
-                                children = orphan_buffer[&parent_hs].clone();
+                        Err(e) => {
+                            debug!("Rejected compact block {}: {}", hash, e);
+                            let penalty = block_penalty(&e);
+                            if penalty > 0 {
+                                self.server.misbehaving(*peer.addr(), penalty, &format!("invalid block: {}", e));
                             }
-                            children.push(blk);
-                            orphan_buffer.insert(parent_hs, children);
+                            continue;
                         }
                     }
-                    if !missing_parents.is_empty() {
-                        peer.write(Message::GetBlocks(missing_parents));
+                    // only count the blocks that are new, announcing known ones costs the same
+                    // either way
+                    let mut stats = self.server.relay_stats().lock().unwrap();
+                    stats.compact_blocks += 1;
+                    stats.compact_bytes += size as u64;
+                    drop(stats);
+                    let partial = PartialBlock::new(compact, &self.mempool.lock().unwrap());
+                    let missing = partial.missing();
+                    if missing.is_empty() {
+                        self.rebuilt_block(&mut peer, hash, partial);
+                        continue;
                     }
-                    // Orphan block handler
-                    let mut left_new_blocks: Vec<H256> = vec![];
-                    left_new_blocks = new_blocks.clone();
-                    while !left_new_blocks.is_empty() {
-                        let mut left_new_blocks1: Vec<H256> = vec![];
-                        for blk_hs in left_new_blocks {
-                            if orphan_buffer.contains_key(&blk_hs) {
-                                let children = &orphan_buffer[&blk_hs];
-                                for child in children {
-                                    if child.get_difficulty() == difficulty {
-                                        if self.tx_signature_check(child.clone()) {
-                                            // update blockchain
-                                            blockchain.insert(&child.clone());
-                                            let mut state_per_block = self.state_per_block.lock().unwrap();
-                                            state_per_block.update(&child);
-                                            drop(state_per_block);
-                                            left_new_blocks1.push(child.hash());
-                                            new_blocks.push(child.hash());
-
-                                            // update mempool
-                                            for tx in &child.content.data {
-                                                mempool.tx_map.remove(&tx.hash());
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            orphan_buffer.remove(&blk_hs);
-                        }
-                        left_new_blocks = left_new_blocks1;
+                    let mut partial_blocks = self.partial_blocks.lock().unwrap();
+                    partial_blocks.retain(|_, (_, received)| received.elapsed() < PARTIAL_BLOCK_TIMEOUT);
+                    if partial_blocks.len() >= MAX_PARTIAL_BLOCKS {
+                        drop(partial_blocks);
+                        self.server.relay_stats().lock().unwrap().fallbacks += 1;
+                        peer.write(Message::GetBlocks(vec![hash]));
+                        continue;
                     }
+                    partial_blocks.insert(hash, (partial, Instant::now()));
+                    drop(partial_blocks);
+                    peer.write(Message::GetBlockTxn(hash, missing));
+                }
+
+                Message::GetBlockTxn(hash, indexes) => {
+                    let block = match self.blockchain.lock().unwrap().get(&hash) {
+                        Some(block) => block,
+                        None => continue,
+                    };
+                    let txs: Option<Vec<SignedTransaction>> = indexes
+                        .iter()
+                        .map(|i| block.content.data.get(*i as usize).cloned())
+                        .collect();
+                    match txs {
+                        Some(txs) => peer.write(Message::BlockTxn(hash, txs)),
+                        None => debug!("Peer {} asked for transactions out of block {}", peer.addr(), hash),
+                    }
+                }
+
+                Message::BlockTxn(hash, txs) => {
+                    let mut stats = self.server.relay_stats().lock().unwrap();
+                    stats.compact_bytes += size as u64;
+                    stats.missing_txs += txs.len() as u64;
+                    drop(stats);
+                    let mut partial = match self.partial_blocks.lock().unwrap().remove(&hash) {
+                        Some((partial, _)) => partial,
+                        None => continue,
+                    };
+                    if partial.fill(txs) {
+                        self.rebuilt_block(&mut peer, hash, partial);
+                    } else {
+                        debug!("Peer {} sent the wrong transactions for block {}", peer.addr(), hash);
+                        self.server.relay_stats().lock().unwrap().fallbacks += 1;
+                        peer.write(Message::GetBlocks(vec![hash]));
+                    }
+                }
+
+                Message::GetHeaders(locator) => {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let headers = blockchain.headers_after(&locator, MAX_HEADERS);
+                    drop(blockchain);
+                    if !headers.is_empty() {
+                        peer.write(Message::Headers(headers));
+                    }
+                }
 
-                    if !new_blocks.is_empty() {
-                        self.server.broadcast(Message::NewBlockHashes(new_blocks));
+                Message::Headers(headers) => {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let mut sync = self.sync.lock().unwrap();
+                    match sync.accept_headers(&headers, &blockchain) {
+                        Ok(new) => {
+                            debug!("Got {} new headers from {}", new, peer.addr());
+                            // a full message means the peer may have more, unless the ones sent
+                            // were not kept
+                            let last = headers.last().unwrap().hash();
+                            if headers.len() == MAX_HEADERS && (blockchain.has(last) || sync.is_pending(&last)) {
+                                let mut locator = vec![last];
+                                locator.extend(blockchain.locator());
+                                peer.write(Message::GetHeaders(locator));
+                            }
+                        }
+                        Err(e) => {
+                            debug!("Rejected headers from {}: {}", peer.addr(), e);
+                            let penalty = block_penalty(&e).max(UNCONNECTED_HEADERS_PENALTY);
+                            self.server.misbehaving(*peer.addr(), penalty, &format!("invalid headers: {}", e));
+                        }
                     }
+                    sync.add_peer(&peer);
+                    self.request_blocks(&mut sync, &blockchain);
                 }
 
                 Message::NewTransactionHashes(hashVec) => {
                     let mut new_tx_hashes = Vec::new();
                     let mut mempool = self.mempool.lock().unwrap();
+                    let mut tx_requests = self.tx_requests.lock().unwrap();
                     for hs in hashVec.iter() {
-                        if !mempool.tx_map.contains_key(hs) {
+                        peer.mark_known(*hs);
+                        if !mempool.contains(hs) && tx_requests.announced(*hs, &peer) {
                             new_tx_hashes.push(hs.clone());
                         }
                     }
+                    drop(tx_requests);
                     if new_tx_hashes.len() > 0 {
                         peer.write(Message::GetTransactions(new_tx_hashes));
                     }
@@ -223,8 +539,9 @@ impl Worker {
                     let mut msg = Vec::new();
                     let mut mempool = self.mempool.lock().unwrap();
                     for hs in hashVec {
-                        if mempool.tx_map.contains_key(&hs) {
-                            msg.push(mempool.tx_map[&hs].clone());
+                        if let Some(tx) = mempool.get(&hs) {
+                            msg.push(tx.clone());
+                            peer.mark_known(hs);
                         }
                     }
                     if !msg.is_empty() {
@@ -235,11 +552,45 @@ impl Worker {
                 Message::Transactions(txVec) => {
                     let mut mempool = self.mempool.lock().unwrap();
                     let mut new_txs: Vec<H256> = vec![];
+                    let mut tx_requests = self.tx_requests.lock().unwrap();
                     for tx in txVec {
-                        if transaction::verify(&tx.transaction, &tx.public_key, &tx.signature) {
-                            mempool.insert(&tx);
+                        let hash = tx.hash();
+                        peer.mark_known(hash);
+                        tx_requests.received(&hash);
+                        match mempool.insert(&tx) {
+                            Ok(()) => new_txs.push(hash),
+                            Err(MempoolError::InvalidSignature) => {
+                                debug!("Rejected transaction {}: {}", tx.hash(), MempoolError::InvalidSignature);
+                                self.server.misbehaving(*peer.addr(), INVALID_TRANSACTION_PENALTY, "invalid transaction signature");
+                            }
+                            Err(e) => debug!("Rejected transaction {}: {}", tx.hash(), e),
                         }
                     }
+                    drop(tx_requests);
+                    drop(mempool);
+                    // pass them on, peers that already have them are skipped
+                    if !new_txs.is_empty() {
+                        self.server.broadcast(Message::NewTransactionHashes(new_txs));
+                    }
+                }
+
+                Message::GetAddr => {
+                    let addrs = self.server.address_book().lock().unwrap().sample(MAX_ADDRS_PER_MESSAGE);
+                    if !addrs.is_empty() {
+                        peer.write(Message::Addr(addrs));
+                    }
+                }
+
+                Message::Addr(addrs) => {
+                    if addrs.len() > MAX_ADDRS_PER_MESSAGE {
+                        debug!("Ignoring {} addresses from {}", addrs.len(), peer.addr());
+                        self.server.misbehaving(*peer.addr(), OVERSIZED_MESSAGE_PENALTY, "too many addresses");
+                        continue;
+                    }
+                    let mut addr_book = self.server.address_book().lock().unwrap();
+                    for addr in addrs {
+                        addr_book.add(addr);
+                    }
                 }
 
                 _ => {}
@@ -11,20 +11,29 @@ use log::info;
 use crate::types::hash::{Hashable, H256};
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::sync::{Arc, Mutex};
-use rand::{Rng, thread_rng};
+use rand::Rng;
+use rand::seq::IteratorRandom;
+use std::collections::HashMap;
 use std::thread;
 use std::time;
 use ring::agreement::PublicKey;
 use crate::types::state::StatePerBlock;
 use crate::types::{key_pair, transaction};
 
+/// The wallet stops creating new accounts once it holds this many keys
+const MAX_WALLET_KEYS: usize = 100;
+/// Generated transactions pay a random fee up to this much
+const MAX_TX_FEE: u32 = 10;
+
 enum ControlSignal {
     Start(u64), 
     Update,     
+    Pause,
     Exit,
 }
 
-enum OperatingState {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperatingState {
     Paused,
     Run(u64),
     ShutDown,
@@ -38,13 +47,24 @@ pub struct Context {
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
     state_per_block: Arc<Mutex<StatePerBlock>>,
-    key_pair: Ed25519KeyPair,
+    /// Key pairs of the accounts this generator can spend from, starting with the ICO account
+    wallet: HashMap<Address, Ed25519KeyPair>,
+    status: Arc<Mutex<Status>>,
+}
+
+/// What the generator is doing, as reported by the API.
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub state: OperatingState,
+    pub transactions_generated: u64,
+    pub last_transaction: Option<H256>,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    status: Arc<Mutex<Status>>,
 }
 
 //double check
@@ -52,9 +72,14 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>, s
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (tx_sender, tx_receiver) = unbounded();
 
-    let mut rng = rand::thread_rng();
-    let mut _source: [u8; 32] = rng.gen();
-    let key_pair = Ed25519KeyPair::from_seed_unchecked(&_source).unwrap();
+    let ico_key = key_pair::ico();
+    let mut wallet = HashMap::new();
+    wallet.insert(Address::from_public_key_bytes(ico_key.public_key().as_ref()), ico_key);
+    let status = Arc::new(Mutex::new(Status {
+        state: OperatingState::Paused,
+        transactions_generated: 0,
+        last_transaction: None,
+    }));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
@@ -63,11 +88,13 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>, s
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
         state_per_block: Arc::clone(state_per_block),
-        key_pair,
+        wallet,
+        status: Arc::clone(&status),
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        status,
     };
 
     (ctx, handle, tx_receiver)
@@ -75,19 +102,32 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>, s
 
 
 
+// the signals return false once the generator has shut down
 impl Handle {
-    pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+    pub fn exit(&self) -> bool {
+        self.control_chan.send(ControlSignal::Exit).is_ok()
     }
 
-    pub fn start(&self, lambda: u64) {
+    pub fn start(&self, lambda: u64) -> bool {
         self.control_chan
             .send(ControlSignal::Start(lambda))
-            .unwrap();
+            .is_ok()
+    }
+
+    pub fn pause(&self) -> bool {
+        self.control_chan.send(ControlSignal::Pause).is_ok()
+    }
+
+    pub fn update(&self) -> bool {
+        self.control_chan.send(ControlSignal::Update).is_ok()
+    }
+
+    pub fn state(&self) -> OperatingState {
+        self.status.lock().unwrap().state
     }
 
-    pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
     }
 }
 
@@ -102,6 +142,11 @@ impl Context {
         info!("tx initialized into paused mode");
     }
 
+    fn set_state(&mut self, state: OperatingState) {
+        self.operating_state = state;
+        self.status.lock().unwrap().state = state;
+    }
+
     fn tx_loop(&mut self) {
         loop {
             // check and react to control signals
@@ -111,13 +156,13 @@ impl Context {
                     match signal {
                         ControlSignal::Exit => {
                             info!("tx shutting down");
-                            self.operating_state = OperatingState::ShutDown;
+                            self.set_state(OperatingState::ShutDown);
                         }
                         ControlSignal::Start(i) => {
                             info!("tx starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
+                            self.set_state(OperatingState::Run(i));
                         }
-                        ControlSignal::Update => {
+                        ControlSignal::Update | ControlSignal::Pause => {
                             // in paused state, don't need to update
                         }
                     };
@@ -131,11 +176,15 @@ impl Context {
                         match signal {
                             ControlSignal::Exit => {
                                 info!("tx shutting down");
-                                self.operating_state = OperatingState::ShutDown;
+                                self.set_state(OperatingState::ShutDown);
                             }
                             ControlSignal::Start(i) => {
                                 info!("tx starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
+                                self.set_state(OperatingState::Run(i));
+                            }
+                            ControlSignal::Pause => {
+                                info!("tx paused");
+                                self.set_state(OperatingState::Paused);
                             }
                             ControlSignal::Update => {
                                 //unimplemented!()
@@ -146,12 +195,19 @@ impl Context {
                     Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                 },
             }
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
+            match self.operating_state {
+                OperatingState::ShutDown => return,
+                OperatingState::Paused => continue,
+                OperatingState::Run(_) => {}
             }
 
-            let t: SignedTransaction = self.generate_valid_transaction();
-            self.tx_chan.send(t.clone()).expect("Send random transaction error");
+            if let Some(t) = self.generate_valid_transaction() {
+                let mut status = self.status.lock().unwrap();
+                status.transactions_generated += 1;
+                status.last_transaction = Some(t.hash());
+                drop(status);
+                self.tx_chan.send(t).expect("Send random transaction error");
+            }
 
             if let OperatingState::Run(i) = self.operating_state {
                 if i != 0 {
@@ -162,92 +218,60 @@ impl Context {
         }
     }
 
-    pub fn get_valid_source_and_amount_and_nonce(&self) -> (Address, u32, u32) {
+    /// Pick a funded account from the tip state that the wallet holds the key of, with an amount
+    /// to spend (value plus fee) and the next nonce. The sender's transactions already in the
+    /// mempool count as spent, so the new one goes after them.
+    pub fn get_valid_source_and_amount_and_nonce(&self) -> Option<(Address, u32, u32)> {
         let blockchain = self.blockchain.lock().unwrap();
+        let mempool = self.mempool.lock().unwrap();
         let state_per_block = self.state_per_block.lock().unwrap();
-        let tip = blockchain.tip();
-
-        let latest_state = &state_per_block.hash_state_map[&blockchain.hash_block_map[&tip].hash()].clone();
-        drop(blockchain);
+        let latest_state = &state_per_block.hash_state_map[&blockchain.tip()];
+        let funded: Vec<(Address, (u32, u32))> = self
+            .wallet
+            .keys()
+            .filter_map(|account| Some((*account, mempool.pending_account(account, latest_state)?)))
+            .filter(|(_, (_, balance))| *balance > 0)
+            .collect();
         drop(state_per_block);
+        drop(mempool);
+        drop(blockchain);
 
         let mut rng = rand::thread_rng();
-
-        let mut valid_source: Address = Address::generate_random_address();
-        let mut valid_amount: u32 = 0;
-        let mut valid_nonce: u32 = 0;
-
-        let mut loop_time: usize = 1;
-
-        if latest_state.state.keys().len() == 0 {
-            loop_time = 1
-        } else {
-            loop_time = rng.gen_range(0..latest_state.state.keys().len());
-        }
-        let mut counter = 0;
-
-        for (account, nonce_and_balance) in latest_state.state.iter() {
-            valid_source = *account;
-            if (*nonce_and_balance).1 == 0 {
-                valid_amount = 0;
-            } else {
-                valid_amount = rng.gen_range(0..(*nonce_and_balance).1);
-            }
-            valid_nonce = (*nonce_and_balance).0 + 1;
-            counter += 1;
-            if counter == loop_time {
-                break;
-            }
-        }
-
-        return (valid_source, valid_amount, valid_nonce);
+        let (source, (nonce, balance)) = funded.into_iter().choose(&mut rng)?;
+        let amount = rng.gen_range(0..balance);
+        Some((source, amount, nonce + 1))
     }
 
-    fn get_valid_destination(&self) -> Address {
-        let blockchain = self.blockchain.lock().unwrap();
-        let tip = blockchain.tip();
-        let state_per_block = self.state_per_block.lock().unwrap();
-        let latest_state = &state_per_block.hash_state_map[&blockchain.hash_block_map[&tip].hash()].clone();
-        drop(state_per_block);
-        drop(blockchain);
+    /// Pick a receiver: either an account already in the wallet, or a new one whose key is kept
+    /// so it can be spent from later
+    fn get_valid_destination(&mut self, sender: &Address) -> Address {
         let mut rng = rand::thread_rng();
-        let mut valid_destination: Address = Address::generate_random_address();
-        let rand_val = rng.gen::<u8>();
-        if rand_val % 2 == 0 && latest_state.state.keys().len() > 1{
-            let mut loop_time = rng.gen_range(0..latest_state.state.keys().len());
-            let mut counter = 0;
-            for (account, nonce_and_balance) in latest_state.state.iter() {
-                valid_destination = *account;
-                counter += 1;
-                if counter == loop_time {
-                    break;
-                }
+        let existing = self.wallet.keys().filter(|a| *a != sender).choose(&mut rng).copied();
+        match existing {
+            Some(address) if self.wallet.len() >= MAX_WALLET_KEYS || rng.gen::<bool>() => address,
+            _ => {
+                let key = key_pair::random();
+                let address = Address::from_public_key_bytes(key.public_key().as_ref());
+                self.wallet.insert(address, key);
+                address
             }
         }
-        return valid_destination;
     }
 
-    fn generate_valid_transaction(&self) -> SignedTransaction {
-        let src_address_and_amount_and_nonce: (Address, u32, u32) = self.get_valid_source_and_amount_and_nonce();
-        let sender = src_address_and_amount_and_nonce.0;
-        let value = src_address_and_amount_and_nonce.1;
-        let acc_nonce = src_address_and_amount_and_nonce.2;
-        let key = key_pair::random();
-        let public_key = key.public_key();
-
-        let receiver: Address = self.get_valid_destination();
+    fn generate_valid_transaction(&mut self) -> Option<SignedTransaction> {
+        let (sender, amount, acc_nonce) = self.get_valid_source_and_amount_and_nonce()?;
+        let fee = rand::thread_rng().gen_range(0..=amount.min(MAX_TX_FEE));
+        let value = amount - fee;
+        let receiver: Address = self.get_valid_destination(&sender);
+        let key = &self.wallet[&sender];
 
-        let transaction = Transaction{sender, receiver, value, acc_nonce};
-        let signature = sign(&transaction, &key);
+        let transaction = Transaction{sender, receiver, value, acc_nonce, fee};
+        let signature = sign(&transaction, key);
         let t = SignedTransaction{
-            transaction: transaction,
+            transaction,
             signature: signature.as_ref().to_vec(),
-            public_key: public_key.as_ref().to_vec()
+            public_key: key.public_key().as_ref().to_vec()
         };
-        return t;
+        Some(t)
     }
 }
-
-
-
-
@@ -1,5 +1,5 @@
 use crossbeam::channel::{Receiver};
-use log::{info};
+use log::{debug, info};
 use crate::network::server::Handle as ServerHandle;
 use std::thread;
 use std::sync::{Arc, Mutex};
@@ -43,8 +43,12 @@ impl Worker {
         loop {
             let t: SignedTransaction = self.tx_chan.recv().expect("Receive finished block error");
             let mut mempool = self.mempool.lock().unwrap();
-            mempool.insert(&t);
+            let inserted = mempool.insert(&t);
             drop(mempool);
+            if let Err(e) = inserted {
+                debug!("Generated transaction {} rejected: {}", t.hash(), e);
+                continue;
+            }
             self.server.broadcast(Message::NewTransactionHashes(vec![t.hash()]));
         }
     }
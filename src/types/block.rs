@@ -2,7 +2,7 @@ use serde::{Serialize, Deserialize};
 use crate::types::hash::{H256, Hashable};
 use ring::digest::{digest, SHA256};
 use rand::Rng;
-use crate::types::transaction::SignedTransaction;
+use crate::types::transaction::{Coinbase, SignedTransaction};
 use crate::types::merkle::MerkleTree;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -26,6 +26,8 @@ pub struct Header {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Content {
+    /// Every block has one except the genesis block
+    pub coinbase: Option<Coinbase>,
     pub data: Vec<SignedTransaction>,
 }
 
@@ -57,6 +59,24 @@ impl Block {
     }
 }
 
+impl Content {
+    /// The merkle root of the coinbase followed by the transactions, which the header commits to
+    pub fn merkle_root(&self) -> H256 {
+        let leaves: Vec<H256> = self
+            .coinbase
+            .iter()
+            .map(|c| c.hash())
+            .chain(self.data.iter().map(|t| t.hash()))
+            .collect();
+        MerkleTree::new(&leaves).root()
+    }
+
+    /// Sum of the fees of the transactions, `None` on overflow
+    pub fn total_fees(&self) -> Option<u32> {
+        self.data.iter().try_fold(0u32, |acc, t| acc.checked_add(t.transaction.fee))
+    }
+}
+
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_block(parent: &H256) -> Block {
     let mut rng = rand::thread_rng();
@@ -72,6 +92,7 @@ pub fn generate_random_block(parent: &H256) -> Block {
             merkle_root: merkle_tree.root()
         },
         content: Content {
+            coinbase: None,
             data: t_vec
         }
     }
@@ -1,6 +1,17 @@
 use ring::rand;
 use ring::signature::Ed25519KeyPair;
 
+/// Seed of the key pair that owns the ICO account.
+const ICO_SEED: [u8; 32] = [
+    0x49, 0x43, 0x4f, 0x20, 0x6b, 0x65, 0x79, 0x20, 0x73, 0x65, 0x65, 0x64, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+];
+
+/// Get the key pair that owns the ICO account, it is the same on every node.
+pub fn ico() -> Ed25519KeyPair {
+    Ed25519KeyPair::from_seed_unchecked(&ICO_SEED).unwrap()
+}
+
 /// Generate a random key pair.
 pub fn random() -> Ed25519KeyPair {
     let rng = rand::SystemRandom::new();
@@ -1,27 +1,418 @@
 /* Mempool */
-use std::collections::HashMap;
+use crossbeam::channel::{bounded, Receiver, Sender, TrySendError};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
 use crate::{H256, Hashable};
-use crate::types::transaction::SignedTransaction;
+use crate::types::address::Address;
+use crate::types::state::State;
+use crate::types::transaction::{self, SignedTransaction};
 
+/// Limits on what the mempool holds.
+#[derive(Debug, Clone, Copy)]
+pub struct MempoolConfig {
+    pub max_txs: usize,
+    /// Total serialized size of the transactions
+    pub max_bytes: usize,
+    /// How long a transaction may wait before it is dropped
+    pub expiry: Duration,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        MempoolConfig {
+            max_txs: 5000,
+            max_bytes: 8 * 1024 * 1024,
+            expiry: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
+/// Reasons for refusing a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    /// The transaction is not signed by its sender
+    InvalidSignature,
+    /// The transaction is already in the mempool
+    AlreadyKnown,
+    /// The sender has a transaction with the same nonce that pays at least as much fee
+    ReplacementUnderpriced,
+    /// The mempool is full and the transaction pays less than anything that could be evicted
+    Full,
+}
+
+impl std::fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MempoolError::InvalidSignature => write!(f, "not signed by the sender"),
+            MempoolError::AlreadyKnown => write!(f, "already in the mempool"),
+            MempoolError::ReplacementUnderpriced => write!(f, "replacement fee not higher"),
+            MempoolError::Full => write!(f, "mempool full"),
+        }
+    }
+}
+
+/// Pending transactions, indexed by hash and by sender and account nonce.
+///
+/// A sender has at most one transaction per nonce. A transaction is ready when it and all the
+/// sender's transactions before it apply on top of a given state, the others are kept as future
+/// transactions until the nonces before them are filled in.
 #[derive(Debug, Default, Clone)]
 pub struct Mempool{
-    pub tx_map: HashMap<H256, SignedTransaction>,
+    tx_map: HashMap<H256, SignedTransaction>,
+    by_sender: HashMap<Address, BTreeMap<u32, H256>>,
+    arrival: HashMap<H256, Instant>,
+    bytes: usize,
+    config: MempoolConfig,
+    /// Notified of every transaction added
+    subscribers: Vec<Sender<H256>>,
 }
 
 impl Mempool {
     pub fn new() -> Self {
-        return Mempool{tx_map: HashMap::new()}
+        Self::with_config(MempoolConfig::default())
+    }
+
+    pub fn with_config(config: MempoolConfig) -> Self {
+        Mempool {
+            tx_map: HashMap::new(),
+            by_sender: HashMap::new(),
+            arrival: HashMap::new(),
+            bytes: 0,
+            config,
+            subscribers: vec![],
+        }
+    }
+
+    /// Get notified when a transaction is added. Notifications are coalesced: while one is
+    /// waiting to be received, later transactions are not queued.
+    pub fn subscribe(&mut self) -> Receiver<H256> {
+        let (sender, receiver) = bounded(1);
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Insert a transaction. A higher fee replaces the sender's pending transaction with the same
+    /// nonce. When the mempool is over its limits, the lowest fee rate transactions are evicted,
+    /// which may be the new one. Transactions past their expiry are dropped first.
+    pub fn insert(&mut self, t: &SignedTransaction) -> Result<(), MempoolError> {
+        self.insert_at(t, Instant::now())
+    }
+
+    fn insert_at(&mut self, t: &SignedTransaction, now: Instant) -> Result<(), MempoolError> {
+        self.expire_at(now);
+        if !transaction::verify_sender(t) {
+            return Err(MempoolError::InvalidSignature);
+        }
+        let t_hash = t.hash();
+        if self.tx_map.contains_key(&t_hash) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+        let sender = t.transaction.sender;
+        let nonce = t.transaction.acc_nonce;
+        let mut replaced = None;
+        if let Some(old_hash) = self.by_sender.get(&sender).and_then(|q| q.get(&nonce)).copied() {
+            if self.tx_map[&old_hash].transaction.fee >= t.transaction.fee {
+                return Err(MempoolError::ReplacementUnderpriced);
+            }
+            let old = self.tx_map[&old_hash].clone();
+            let arrival = self.arrival[&old_hash];
+            self.remove(&old);
+            replaced = Some((old, arrival));
+        }
+        self.add(t, now);
+
+        while self.tx_map.len() > self.config.max_txs || self.bytes > self.config.max_bytes {
+            let evicted = self.lowest_fee_rate_tail();
+            self.remove(&evicted);
+            if evicted.hash() == t_hash {
+                // the sender keeps its nonce filled with the transaction it had
+                if let Some((old, arrival)) = replaced {
+                    self.add(&old, arrival);
+                }
+                return Err(MempoolError::Full);
+            }
+        }
+        self.subscribers
+            .retain(|s| !matches!(s.try_send(t_hash), Err(TrySendError::Disconnected(_))));
+        Ok(())
     }
 
-    pub fn insert(&mut self, t: &SignedTransaction) {
+    /// Index a transaction, without checking anything
+    fn add(&mut self, t: &SignedTransaction, arrival: Instant) {
         let t_hash = t.hash();
+        self.by_sender.entry(t.transaction.sender).or_default().insert(t.transaction.acc_nonce, t_hash);
+        self.arrival.insert(t_hash, arrival);
+        self.bytes += t.size();
         self.tx_map.insert(t_hash, t.clone());
     }
 
+    /// Get the transaction with the lowest fee rate among the last pending transaction of every
+    /// sender, so evicting it never leaves a gap in a sender's nonces
+    fn lowest_fee_rate_tail(&self) -> SignedTransaction {
+        self.by_sender
+            .values()
+            .filter_map(|q| q.values().next_back())
+            .map(|h| &self.tx_map[h])
+            .min_by(|a, b| a.cmp_fee_rate(b))
+            .expect("Evicting from an empty mempool")
+            .clone()
+    }
+
+    /// Drop the transactions that have waited longer than the configured expiry
+    pub fn expire(&mut self) {
+        self.expire_at(Instant::now());
+    }
+
+    fn expire_at(&mut self, now: Instant) {
+        let expiry = self.config.expiry;
+        let expired: Vec<SignedTransaction> = self
+            .arrival
+            .iter()
+            .filter(|(_, t)| now.saturating_duration_since(**t) > expiry)
+            .map(|(h, _)| self.tx_map[h].clone())
+            .collect();
+        for tx in expired {
+            self.remove(&tx);
+        }
+    }
+
+    /// Get, for every sender, the longest run of transactions that applies in order on top of
+    /// `state`, starting at the sender's next nonce
+    pub fn ready(&self, state: &State) -> Vec<Vec<SignedTransaction>> {
+        let mut sequences = vec![];
+        for (sender, queue) in self.by_sender.iter() {
+            let account = match state.state.get(sender) {
+                Some(account) => *account,
+                None => continue,
+            };
+            let (sequence, _) = self.ready_run(queue, account);
+            if !sequence.is_empty() {
+                sequences.push(sequence.into_iter().cloned().collect());
+            }
+        }
+        sequences
+    }
+
+    /// Get the nonce and balance of `sender` once its ready transactions are applied on top of
+    /// `state`, which is where a new transaction from it goes
+    pub fn pending_account(&self, sender: &Address, state: &State) -> Option<(u32, u32)> {
+        let account = *state.state.get(sender)?;
+        match self.by_sender.get(sender) {
+            Some(queue) => Some(self.ready_run(queue, account).1),
+            None => Some(account),
+        }
+    }
+
+    /// Get the sender's transactions in `queue` that apply in order on top of its `account`
+    /// nonce and balance, with the nonce and balance after them
+    fn ready_run(&self, queue: &BTreeMap<u32, H256>, account: (u32, u32)) -> (Vec<&SignedTransaction>, (u32, u32)) {
+        let (mut nonce, mut balance) = account;
+        let mut sequence = vec![];
+        for (tx_nonce, tx_hash) in queue.range(nonce + 1..) {
+            let tx = &self.tx_map[tx_hash];
+            let cost = match tx.transaction.value.checked_add(tx.transaction.fee) {
+                Some(c) => c,
+                None => break,
+            };
+            if *tx_nonce != nonce + 1 || cost > balance {
+                break;
+            }
+            nonce += 1;
+            balance -= cost;
+            sequence.push(tx);
+        }
+        (sequence, (nonce, balance))
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.tx_map.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&SignedTransaction> {
+        self.tx_map.get(hash)
+    }
+
+    /// Iterate over the pending transactions and their hashes, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&H256, &SignedTransaction)> {
+        self.tx_map.iter()
+    }
+
+    /// Number of pending transactions, ready or not
+    pub fn len(&self) -> usize {
+        self.tx_map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tx_map.is_empty()
+    }
+
+    /// Total serialized size of the pending transactions
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
     pub fn remove(&mut self, t: &SignedTransaction) {
         let t_hash = t.hash();
-        if self.tx_map.contains_key(&t_hash) {
-            self.tx_map.remove(&t_hash);
+        if self.tx_map.remove(&t_hash).is_none() {
+            return;
+        }
+        self.arrival.remove(&t_hash);
+        self.bytes -= t.size();
+        let sender = t.transaction.sender;
+        if let Some(queue) = self.by_sender.get_mut(&sender) {
+            queue.remove(&t.transaction.acc_nonce);
+            if queue.is_empty() {
+                self.by_sender.remove(&sender);
+            }
+        }
+    }
+
+    /// Drop the transactions whose nonce is already used in `state`, they can never apply
+    pub fn prune(&mut self, state: &State) {
+        let mut stale = vec![];
+        for (sender, queue) in self.by_sender.iter() {
+            let nonce = state.state.get(sender).map(|a| a.0).unwrap_or(0);
+            stale.extend(queue.range(..=nonce).map(|(_, h)| *h));
+        }
+        for tx_hash in stale {
+            let tx = self.tx_map[&tx_hash].clone();
+            self.remove(&tx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use crate::types::transaction::{sign, Transaction};
+    use ring::signature::KeyPair;
+
+    fn ico_transfer(acc_nonce: u32, fee: u32) -> SignedTransaction {
+        let key = key_pair::ico();
+        let transaction = Transaction {
+            sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+            acc_nonce,
+            receiver: Address::generate_random_address(),
+            value: 1,
+            fee,
+        };
+        SignedTransaction {
+            signature: sign(&transaction, &key).as_ref().to_vec(),
+            public_key: key.public_key().as_ref().to_vec(),
+            transaction,
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn ready_and_replace_by_fee() {
+        let key = key_pair::ico();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut state = State::new();
+        state.state.insert(sender, (0, 1000));
+        let mut mempool = Mempool::new();
+
+        // nonce 3 waits for nonce 2
+        assert_eq!(mempool.insert(&ico_transfer(1, 1)), Ok(()));
+        assert_eq!(mempool.insert(&ico_transfer(3, 1)), Ok(()));
+        let nonces = |m: &Mempool, s: &State| -> Vec<u32> {
+            m.ready(s).concat().iter().map(|t| t.transaction.acc_nonce).collect()
+        };
+        assert_eq!(nonces(&mempool, &state), vec![1]);
+        assert_eq!(mempool.insert(&ico_transfer(2, 1)), Ok(()));
+        assert_eq!(nonces(&mempool, &state), vec![1, 2, 3]);
+
+        // same nonce only replaces with a higher fee
+        assert_eq!(mempool.insert(&ico_transfer(2, 1)), Err(MempoolError::ReplacementUnderpriced));
+        let bump = ico_transfer(2, 5);
+        assert_eq!(mempool.insert(&bump), Ok(()));
+        assert_eq!(mempool.insert(&bump), Err(MempoolError::AlreadyKnown));
+        assert_eq!(mempool.len(), 3);
+        assert!(mempool.ready(&state).concat().iter().any(|t| t.hash() == bump.hash()));
+
+        // a new transaction from the sender goes after the ready ones
+        assert_eq!(mempool.pending_account(&sender, &state), Some((3, 1000 - 2 - 2 - 6)));
+
+        state.state.insert(sender, (2, 1000));
+        mempool.prune(&state);
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(nonces(&mempool, &state), vec![3]);
+    }
+
+    #[test]
+    fn evict_lowest_fee_when_full() {
+        let size = ico_transfer(1, 1).size();
+        let mut mempool = Mempool::with_config(MempoolConfig {
+            max_txs: 3,
+            max_bytes: 3 * size,
+            expiry: Duration::from_secs(60),
+        });
+        let other = key_pair::random();
+        let other_transfer = |acc_nonce: u32, fee: u32| {
+            let mut tx = ico_transfer(acc_nonce, fee);
+            tx.transaction.sender = Address::from_public_key_bytes(other.public_key().as_ref());
+            tx.signature = sign(&tx.transaction, &other).as_ref().to_vec();
+            tx.public_key = other.public_key().as_ref().to_vec();
+            tx
+        };
+
+        assert_eq!(mempool.insert(&ico_transfer(1, 8)), Ok(()));
+        let cheap = ico_transfer(2, 1);
+        assert_eq!(mempool.insert(&cheap), Ok(()));
+        assert_eq!(mempool.insert(&other_transfer(1, 5)), Ok(()));
+        // the ICO account's last transaction pays the least
+        assert_eq!(mempool.insert(&other_transfer(2, 6)), Ok(()));
+        assert_eq!(mempool.len(), 3);
+        assert!(!mempool.contains(&cheap.hash()));
+        assert_eq!(mempool.insert(&cheap), Err(MempoolError::Full));
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.bytes(), 3 * size);
+
+        mempool.config.expiry = Duration::from_secs(0);
+        std::thread::sleep(Duration::from_millis(1));
+        mempool.expire();
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.bytes(), 0);
+    }
+
+    #[test]
+    fn keep_replaced_transaction_when_full() {
+        let mut mempool = Mempool::new();
+        let other = key_pair::random();
+        let mut rich = ico_transfer(1, 8);
+        rich.transaction.sender = Address::from_public_key_bytes(other.public_key().as_ref());
+        rich.signature = sign(&rich.transaction, &other).as_ref().to_vec();
+        rich.public_key = other.public_key().as_ref().to_vec();
+        let original = ico_transfer(1, 1);
+        assert_eq!(mempool.insert(&rich), Ok(()));
+        assert_eq!(mempool.insert(&original), Ok(()));
+        mempool.config.max_txs = 1;
+
+        // the replacement pays more than the original, but still the least in the pool
+        let replacement = ico_transfer(1, 2);
+        assert_eq!(mempool.insert(&replacement), Err(MempoolError::Full));
+        assert!(mempool.contains(&original.hash()));
+        assert!(!mempool.contains(&replacement.hash()));
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool.bytes(), rich.size() + original.size());
+        let sender = original.transaction.sender;
+        assert_eq!(mempool.by_sender[&sender].get(&1), Some(&original.hash()));
+    }
+
+    #[test]
+    fn expire_on_insert_without_blocks() {
+        let mut mempool = Mempool::new();
+        let start = Instant::now();
+        let old = ico_transfer(1, 1);
+        assert_eq!(mempool.insert_at(&old, start), Ok(()));
+        assert_eq!(mempool.insert_at(&ico_transfer(2, 1), start + mempool.config.expiry / 2), Ok(()));
+        assert_eq!(mempool.len(), 2);
+
+        // no block arrives, the next transaction is enough to drop the stale one
+        let later = start + mempool.config.expiry + Duration::from_secs(1);
+        assert_eq!(mempool.insert_at(&ico_transfer(3, 1), later), Ok(()));
+        assert_eq!(mempool.len(), 2);
+        assert!(!mempool.contains(&old.hash()));
+        assert_eq!(mempool.bytes(), 2 * old.size());
+    }
+}
@@ -6,3 +6,4 @@ pub mod key_pair;
 pub mod transaction;
 pub mod state;
 pub mod mempool;
+pub mod u256;
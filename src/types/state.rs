@@ -3,8 +3,9 @@ use std::collections::HashMap;
 use std::ops::Add;
 use crate::{Block, H256, Hashable};
 use crate::types::address::Address;
-use crate::types::transaction::SignedTransaction;
-use hex_literal::hex;
+use crate::types::key_pair;
+use crate::types::transaction::{self, Coinbase, SignedTransaction};
+use ring::signature::KeyPair;
 
 #[derive(Debug, Default, Clone)]
 pub struct State {
@@ -17,10 +18,16 @@ impl State {
     }
 
     pub fn check(&self, t: &SignedTransaction) -> bool {
+        if !transaction::verify_sender(t) {
+            return false;
+        }
         let tx = t.transaction.clone();
         let sender = tx.sender;
         let nonce = tx.acc_nonce;
-        let value = tx.value;
+        let cost = match tx.value.checked_add(tx.fee) {
+            Some(c) => c,
+            None => return false,
+        };
 
         if !self.state.contains_key(&sender) {
             return false;
@@ -28,7 +35,7 @@ impl State {
 
         let new_sender_nonce = self.state[&sender].0 + 1;
 
-        if nonce != new_sender_nonce || self.state[&sender].1 < value {
+        if nonce != new_sender_nonce || self.state[&sender].1 < cost {
             println!("can't update invalid tx");
             return false;
         }
@@ -37,11 +44,18 @@ impl State {
     }
 
     pub fn update(&mut self, t: &SignedTransaction) -> bool {
+        if !transaction::verify_sender(t) {
+            return false;
+        }
         let tx = t.transaction.clone();
         let sender = tx.sender;
         let nonce = tx.acc_nonce;
         let receiver = tx.receiver;
         let value = tx.value;
+        let cost = match value.checked_add(tx.fee) {
+            Some(c) => c,
+            None => return false,
+        };
 
         if !self.state.contains_key(&sender) {
             return false;
@@ -49,12 +63,13 @@ impl State {
 
         let new_sender_nonce = self.state[&sender].0 + 1;
 
-        if nonce != new_sender_nonce || self.state[&sender].1 < value {
+        if nonce != new_sender_nonce || self.state[&sender].1 < cost {
             //println!("can't update invalid tx");
             return false;
         }
 
-        let new_sender_balance = self.state[&sender].1 - value;
+        // the fee leaves the sender here, the block producer claims it separately
+        let new_sender_balance = self.state[&sender].1 - cost;
 
         self.state.insert(sender, (new_sender_nonce, new_sender_balance));
 
@@ -69,6 +84,25 @@ impl State {
         return true;
     }
 
+    /// Credit a coinbase to its receiver, returns false if it mints more than the subsidy at its
+    /// height plus `fees`
+    pub fn apply_coinbase(&mut self, coinbase: &Coinbase, fees: u32) -> bool {
+        let max_value = match transaction::block_subsidy(coinbase.height).checked_add(fees) {
+            Some(v) => v,
+            None => return false,
+        };
+        if coinbase.value > max_value {
+            return false;
+        }
+        let (nonce, balance) = self.state.get(&coinbase.receiver).copied().unwrap_or((0, 0));
+        let balance = match balance.checked_add(coinbase.value) {
+            Some(b) => b,
+            None => return false,
+        };
+        self.state.insert(coinbase.receiver, (nonce, balance));
+        true
+    }
+
     pub fn to_vec_string(&self) -> Vec<String> {
         let mut res: Vec<String> = vec![];
 
@@ -91,7 +125,7 @@ pub struct StatePerBlock {
 impl StatePerBlock {
     pub fn new(tip: H256) -> Self {
         let mut init_state = State::new();
-        let init_acc: Address = hex!("1234567812345678123456781234567812345678").into();
+        let init_acc = Address::from_public_key_bytes(key_pair::ico().public_key().as_ref());
         init_state.state.insert(init_acc, (0, 1000));
         let mut map: HashMap<H256, State> = HashMap::new();
         map.insert(tip, init_state);
@@ -100,14 +134,29 @@ impl StatePerBlock {
         }
     }
 
-    pub fn update(&mut self, block: &Block) {
+    /// Record the state after a block, returns false and records nothing if a transaction does
+    /// not apply or the coinbase is missing or claims too much
+    pub fn update(&mut self, block: &Block) -> bool {
         let mut new_state = self.hash_state_map[&block.get_parent()].clone();
-        let txs = block.clone().content.data;
 
-        for tx in &txs {
-            new_state.update(&tx);
+        for tx in block.content.data.iter() {
+            if !new_state.update(tx) {
+                return false;
+            }
+        }
+        let coinbase = match &block.content.coinbase {
+            Some(c) => c,
+            None => return false,
+        };
+        let fees = match block.content.total_fees() {
+            Some(f) => f,
+            None => return false,
+        };
+        if !new_state.apply_coinbase(coinbase, fees) {
+            return false;
         }
 
         self.hash_state_map.insert(block.hash(), new_state);
+        true
     }
 }
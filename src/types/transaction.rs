@@ -12,6 +12,8 @@ pub struct Transaction {
     pub acc_nonce: u32,
     pub receiver: Address,
     pub value: u32,
+    /// Paid by the sender on top of `value`, goes to the producer of the block that includes it
+    pub fee: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -22,6 +24,37 @@ pub struct SignedTransaction {
 }
 
 
+/// Block subsidy of the first blocks, in the same unit as `Transaction::value`
+pub const INITIAL_SUBSIDY: u32 = 50;
+/// Number of blocks after which the subsidy halves
+pub const HALVING_INTERVAL: u64 = 1000;
+
+/// The first entry of a block: mints the block subsidy and collects the block's fees for the
+/// block producer. It has no sender, so it is not signed.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Coinbase {
+    pub receiver: Address,
+    pub value: u32,
+    /// Height of the block, which also keeps coinbases of different blocks distinct
+    pub height: u64,
+}
+
+impl Hashable for Coinbase {
+    fn hash(&self) -> H256 {
+        digest(&SHA256, &bincode::serialize(&self).unwrap()).into()
+    }
+}
+
+/// Get the subsidy a coinbase may mint at a block height
+pub fn block_subsidy(height: u64) -> u32 {
+    let halvings = height / HALVING_INTERVAL;
+    if halvings >= 32 {
+        0
+    } else {
+        INITIAL_SUBSIDY >> halvings
+    }
+}
+
 impl Hashable for SignedTransaction {
     fn hash(&self) -> H256 {
         digest(&SHA256, &bincode::serialize(&self).unwrap()).into()
@@ -29,6 +62,20 @@ impl Hashable for SignedTransaction {
 }
 
 
+impl SignedTransaction {
+    /// Size of the transaction on the wire, in bytes
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    /// Compare fee per byte with another transaction, without rounding
+    pub fn cmp_fee_rate(&self, other: &SignedTransaction) -> std::cmp::Ordering {
+        let lhs = self.transaction.fee as u64 * other.size() as u64;
+        let rhs = other.transaction.fee as u64 * self.size() as u64;
+        lhs.cmp(&rhs)
+    }
+}
+
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
     let converted_t = bincode::serialize(t).unwrap();
@@ -36,6 +83,13 @@ pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
     sig
 }
 
+/// Verify that a signed transaction is signed by its sender: the signature must verify with the
+/// attached public key, and that public key must hash to the sender's address
+pub fn verify_sender(t: &SignedTransaction) -> bool {
+    Address::from_public_key_bytes(&t.public_key) == t.transaction.sender
+        && verify(&t.transaction, &t.public_key, &t.signature)
+}
+
 /// Verify digital signature of a transaction, using public key instead of secret key
 pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
     let converted_t = bincode::serialize(t).unwrap();
@@ -0,0 +1,177 @@
+use crate::types::hash::H256;
+
+/// An unsigned 256-bit integer, used for arithmetic on difficulty targets.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default, Hash)]
+pub struct U256([u64; 4]); // little endian limbs
+
+impl U256 {
+    pub fn zero() -> Self {
+        U256([0; 4])
+    }
+
+    pub fn max_value() -> Self {
+        U256([u64::MAX; 4])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|l| *l == 0)
+    }
+
+    /// Add, or `None` on overflow.
+    pub fn checked_add(&self, rhs: &U256) -> Option<U256> {
+        let mut res = [0u64; 4];
+        let mut carry = false;
+        for (i, r) in res.iter_mut().enumerate() {
+            let (sum, c1) = self.0[i].overflowing_add(rhs.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            *r = sum;
+            carry = c1 || c2;
+        }
+        if carry {
+            return None;
+        }
+        Some(U256(res))
+    }
+
+    /// Subtract, wrapping around on underflow.
+    fn wrapping_sub(&self, rhs: &U256) -> U256 {
+        let mut res = [0u64; 4];
+        let mut borrow = false;
+        for (i, r) in res.iter_mut().enumerate() {
+            let (diff, b1) = self.0[i].overflowing_sub(rhs.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            *r = diff;
+            borrow = b1 || b2;
+        }
+        U256(res)
+    }
+
+    /// Number of significant bits.
+    fn bits(&self) -> usize {
+        for i in (0..4).rev() {
+            if self.0[i] != 0 {
+                return 64 * i + 64 - self.0[i].leading_zeros() as usize;
+            }
+        }
+        0
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.0[index / 64] >> (index % 64) & 1 == 1
+    }
+
+    fn shl1(&self) -> U256 {
+        let mut res = [0u64; 4];
+        for (i, r) in res.iter_mut().enumerate() {
+            *r = self.0[i] << 1;
+            if i > 0 {
+                *r |= self.0[i - 1] >> 63;
+            }
+        }
+        U256(res)
+    }
+
+    /// Divide by a non-zero `U256`, rounding down.
+    pub fn div(&self, rhs: &U256) -> U256 {
+        assert!(!rhs.is_zero(), "division by zero");
+        let mut quotient = [0u64; 4];
+        let mut rem = U256::zero();
+        for i in (0..self.bits()).rev() {
+            // a remainder shifted past 256 bits is always larger than the divisor
+            let overflow = rem.bit(255);
+            rem = rem.shl1();
+            rem.0[0] |= self.bit(i) as u64;
+            if overflow || rem >= *rhs {
+                rem = rem.wrapping_sub(rhs);
+                quotient[i / 64] |= 1 << (i % 64);
+            }
+        }
+        U256(quotient)
+    }
+
+    /// Bitwise complement.
+    pub fn not(&self) -> U256 {
+        U256([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
+    }
+
+    /// Multiply by a `u64`, or `None` on overflow.
+    pub fn checked_mul_u64(&self, rhs: u64) -> Option<U256> {
+        let mut res = [0u64; 4];
+        let mut carry: u128 = 0;
+        for (i, limb) in self.0.iter().enumerate() {
+            let prod = *limb as u128 * rhs as u128 + carry;
+            res[i] = prod as u64;
+            carry = prod >> 64;
+        }
+        if carry != 0 {
+            return None;
+        }
+        Some(U256(res))
+    }
+
+    /// Divide by a non-zero `u64`, rounding down.
+    pub fn div_u64(&self, rhs: u64) -> U256 {
+        assert!(rhs != 0, "division by zero");
+        let mut res = [0u64; 4];
+        let mut rem: u128 = 0;
+        for i in (0..4).rev() {
+            let cur = (rem << 64) | self.0[i] as u128;
+            res[i] = (cur / rhs as u128) as u64;
+            rem = cur % rhs as u128;
+        }
+        U256(res)
+    }
+}
+
+impl std::convert::From<H256> for U256 {
+    fn from(input: H256) -> U256 {
+        let bytes: [u8; 32] = input.into();
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&bytes[(24 - 8 * i)..(32 - 8 * i)]);
+            *limb = u64::from_be_bytes(buf);
+        }
+        U256(limbs)
+    }
+}
+
+impl std::convert::From<U256> for H256 {
+    fn from(input: U256) -> H256 {
+        let mut bytes = [0u8; 32];
+        for (i, limb) in input.0.iter().enumerate() {
+            bytes[(24 - 8 * i)..(32 - 8 * i)].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes.into()
+    }
+}
+
+impl std::convert::From<u64> for U256 {
+    fn from(input: u64) -> U256 {
+        U256([input, 0, 0, 0])
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &U256) -> std::cmp::Ordering {
+        for i in (0..4).rev() {
+            match self.0[i].cmp(&other.0[i]) {
+                std::cmp::Ordering::Equal => continue,
+                ord => return ord,
+            }
+        }
+        std::cmp::Ordering::Equal
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &U256) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::fmt::Display for U256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Display::fmt(&H256::from(*self), f)
+    }
+}
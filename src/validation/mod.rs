@@ -0,0 +1,257 @@
+use crate::blockchain::Blockchain;
+use crate::types::block::{Block, Header};
+use crate::types::hash::{H256, Hashable};
+use crate::types::state::StatePerBlock;
+use crate::types::transaction;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of ancestors whose median timestamp a new block must be later than.
+const MEDIAN_TIME_SPAN: usize = 11;
+/// How far a block's timestamp may be ahead of our clock, in milliseconds.
+const MAX_FUTURE_DRIFT_MS: u128 = 60 * 1000;
+
+/// Reasons for rejecting a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    /// The block hash is above its difficulty target
+    InsufficientWork,
+    /// The difficulty target is easier than the genesis difficulty
+    TargetAboveLimit,
+    /// The parent is not in the blockchain, the block may become valid once it is
+    UnknownParent(H256),
+    /// The difficulty is not the one the retargeting rule gives for this parent
+    WrongDifficulty { expected: H256, actual: H256 },
+    /// The timestamp is not after the median timestamp of the previous blocks
+    TimestampTooOld,
+    /// The timestamp is too far ahead of the local clock
+    TimestampTooNew,
+    /// The merkle root in the header does not commit to the transactions in the content
+    MerkleRootMismatch,
+    /// A transaction is not signed by its sender
+    InvalidSignature(H256),
+    /// A transaction does not apply on top of the parent state
+    InvalidTransaction(H256),
+    /// The coinbase is missing, has the wrong height, or mints more than the subsidy plus fees
+    InvalidCoinbase,
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockError::InsufficientWork => write!(f, "block hash above difficulty"),
+            BlockError::TargetAboveLimit => write!(f, "difficulty easier than the limit"),
+            BlockError::UnknownParent(p) => write!(f, "unknown parent {}", p),
+            BlockError::WrongDifficulty { expected, actual } => {
+                write!(f, "difficulty {} but expected {}", actual, expected)
+            }
+            BlockError::TimestampTooOld => write!(f, "timestamp not after median time past"),
+            BlockError::TimestampTooNew => write!(f, "timestamp too far in the future"),
+            BlockError::MerkleRootMismatch => write!(f, "merkle root does not match content"),
+            BlockError::InvalidSignature(t) => write!(f, "invalid signature in transaction {}", t),
+            BlockError::InvalidTransaction(t) => write!(f, "transaction {} does not apply to parent state", t),
+            BlockError::InvalidCoinbase => write!(f, "invalid coinbase"),
+        }
+    }
+}
+
+/// Get the median timestamp of `parent` and its ancestors, over at most `MEDIAN_TIME_SPAN` blocks.
+/// Only the parent's ancestors have to be in the blockchain, not the parent itself.
+pub fn median_time_past(blockchain: &Blockchain, parent: &Header) -> u128 {
+    let mut timestamps = vec![parent.timestamp];
+    let mut p = parent.parent;
+    while timestamps.len() < MEDIAN_TIME_SPAN {
+        match blockchain.get_header(&p) {
+            Some(header) => {
+                timestamps.push(header.timestamp);
+                p = header.parent;
+            }
+            None => break,
+        }
+    }
+    timestamps.sort_unstable();
+    timestamps[timestamps.len() / 2]
+}
+
+/// Check a header on its own and against its parent: proof of work, difficulty and timestamp.
+/// The proof of work is checked before the parent, so `UnknownParent` is only returned for
+/// headers that carry valid work.
+pub fn validate_header(header: &Header, blockchain: &Blockchain) -> Result<(), BlockError> {
+    if header.difficulty > blockchain.pow_limit() {
+        return Err(BlockError::TargetAboveLimit);
+    }
+    if header.hash() > header.difficulty {
+        return Err(BlockError::InsufficientWork);
+    }
+
+    let parent = match blockchain.get_header(&header.parent) {
+        Some(p) => p,
+        None => return Err(BlockError::UnknownParent(header.parent)),
+    };
+    let expected = blockchain.expected_difficulty(&header.parent);
+    if header.difficulty != expected {
+        return Err(BlockError::WrongDifficulty {
+            expected,
+            actual: header.difficulty,
+        });
+    }
+
+    if header.timestamp <= median_time_past(blockchain, parent) {
+        return Err(BlockError::TimestampTooOld);
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    if header.timestamp > now + MAX_FUTURE_DRIFT_MS {
+        return Err(BlockError::TimestampTooNew);
+    }
+    Ok(())
+}
+
+/// Fully validate a block before it is inserted: the header, the merkle root, that every
+/// transaction is signed and applies in order on top of the parent's state, and that the coinbase
+/// claims no more than the subsidy and fees.
+pub fn validate_block(block: &Block, blockchain: &Blockchain, state_per_block: &StatePerBlock) -> Result<(), BlockError> {
+    validate_header(&block.header, blockchain)?;
+
+    if block.content.merkle_root() != block.header.merkle_root {
+        return Err(BlockError::MerkleRootMismatch);
+    }
+
+    for tx in block.content.data.iter() {
+        if !transaction::verify_sender(tx) {
+            return Err(BlockError::InvalidSignature(tx.hash()));
+        }
+    }
+
+    let mut state = state_per_block.hash_state_map[&block.get_parent()].clone();
+    for tx in block.content.data.iter() {
+        if !state.update(tx) {
+            return Err(BlockError::InvalidTransaction(tx.hash()));
+        }
+    }
+
+    let coinbase = block.content.coinbase.as_ref().ok_or(BlockError::InvalidCoinbase)?;
+    if coinbase.height != blockchain.height(&block.get_parent()).unwrap() + 1 {
+        return Err(BlockError::InvalidCoinbase);
+    }
+    let fees = block.content.total_fees().ok_or(BlockError::InvalidCoinbase)?;
+    if !state.apply_coinbase(coinbase, fees) {
+        return Err(BlockError::InvalidCoinbase);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::Content;
+    use crate::types::address::Address;
+    use crate::types::hash::generate_random_hash;
+    use crate::types::key_pair;
+    use crate::types::transaction::{block_subsidy, Coinbase, SignedTransaction, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn mine(mut block: Block) -> Block {
+        while block.hash() > block.header.difficulty {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    fn empty_child(blockchain: &Blockchain, parent: H256) -> Block {
+        let height = blockchain.height(&parent).unwrap_or(0) + 1;
+        let coinbase = Coinbase { receiver: Address::generate_random_address(), value: block_subsidy(height), height };
+        let content = Content { coinbase: Some(coinbase), data: vec![] };
+        Block {
+            header: Header {
+                parent,
+                nonce: 0,
+                difficulty: blockchain.expected_difficulty(&parent),
+                timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
+                merkle_root: content.merkle_root(),
+            },
+            content,
+        }
+    }
+
+    fn signed_transfer(key: &Ed25519KeyPair, sender: Address, acc_nonce: u32) -> SignedTransaction {
+        let transaction = Transaction { sender, acc_nonce, receiver: Address::generate_random_address(), value: 10, fee: 1 };
+        let signature = transaction::sign(&transaction, key);
+        SignedTransaction {
+            transaction,
+            signature: signature.as_ref().to_vec(),
+            public_key: key.public_key().as_ref().to_vec(),
+        }
+    }
+
+    #[test]
+    fn sender_must_sign() {
+        let blockchain = Blockchain::new();
+        let state_per_block = StatePerBlock::new(blockchain.genesis());
+        let ico_key = key_pair::ico();
+        let ico_address = Address::from_public_key_bytes(ico_key.public_key().as_ref());
+
+        let mut block = empty_child(&blockchain, blockchain.tip());
+        block.content.data.push(signed_transfer(&ico_key, ico_address, 1));
+        block.header.merkle_root = block.content.merkle_root();
+        assert_eq!(validate_block(&mine(block.clone()), &blockchain, &state_per_block), Ok(()));
+
+        // a valid signature from a key that does not own the account
+        let forged = signed_transfer(&key_pair::random(), ico_address, 1);
+        block.content.data = vec![forged.clone()];
+        block.header.merkle_root = block.content.merkle_root();
+        assert_eq!(
+            validate_block(&mine(block), &blockchain, &state_per_block),
+            Err(BlockError::InvalidSignature(forged.hash()))
+        );
+    }
+
+    #[test]
+    fn block_rejection_reasons() {
+        let blockchain = Blockchain::new();
+        let state_per_block = StatePerBlock::new(blockchain.genesis());
+        let genesis_hash = blockchain.tip();
+
+        let block = mine(empty_child(&blockchain, genesis_hash));
+        assert_eq!(validate_block(&block, &blockchain, &state_per_block), Ok(()));
+
+        let mut orphan = empty_child(&blockchain, genesis_hash);
+        orphan.header.parent = generate_random_hash();
+        let orphan = mine(orphan);
+        assert_eq!(
+            validate_block(&orphan, &blockchain, &state_per_block),
+            Err(BlockError::UnknownParent(orphan.header.parent))
+        );
+
+        let mut harder = empty_child(&blockchain, genesis_hash);
+        harder.header.difficulty = hex!("07ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        let harder = mine(harder);
+        assert!(matches!(
+            validate_block(&harder, &blockchain, &state_per_block),
+            Err(BlockError::WrongDifficulty { .. })
+        ));
+
+        let mut tampered = empty_child(&blockchain, genesis_hash);
+        tampered.header.merkle_root = generate_random_hash();
+        let tampered = mine(tampered);
+        assert_eq!(
+            validate_block(&tampered, &blockchain, &state_per_block),
+            Err(BlockError::MerkleRootMismatch)
+        );
+
+        let mut greedy = empty_child(&blockchain, genesis_hash);
+        greedy.content.coinbase.as_mut().unwrap().value += 1;
+        greedy.header.merkle_root = greedy.content.merkle_root();
+        let greedy = mine(greedy);
+        assert_eq!(
+            validate_block(&greedy, &blockchain, &state_per_block),
+            Err(BlockError::InvalidCoinbase)
+        );
+
+        let mut late = empty_child(&blockchain, genesis_hash);
+        late.header.timestamp += 2 * MAX_FUTURE_DRIFT_MS;
+        let late = mine(late);
+        assert_eq!(
+            validate_block(&late, &blockchain, &state_per_block),
+            Err(BlockError::TimestampTooNew)
+        );
+    }
+}